@@ -2,12 +2,9 @@ use std::fmt;
 
 use crate::{ Move, TTable };
 use crate::moves::MoveType;
+use crate::movegen;
 use crate::board_info::*;
 
-// 2 ^ 14 sized prev move array
-const PREV_MOVE_SIZE: usize = 16384;
-const PREV_MOVE_MASK: u64 = 0x3FFF;
-
 #[derive(Debug, Clone)]
 pub struct Board {
     pub pieces: [u64; 12],
@@ -24,7 +21,10 @@ pub struct Board {
     pub fullmove: u8,
 
     pub hash: u64,
-    pub prev_moves: [u8; PREV_MOVE_SIZE],
+    // hash of every position before this one in the game and search, for finding repetitions
+    pub hash_history: Vec<u64>,
+    // length of hash_history just after each null move still being searched, repetitions cant cross one
+    pub null_moves: Vec<usize>,
 }
 
 impl Board {
@@ -40,7 +40,8 @@ impl Board {
             halfmove: 0,
             fullmove: 1,
             hash: 0,
-            prev_moves: [0; PREV_MOVE_SIZE]
+            hash_history: Vec::new(),
+            null_moves: Vec::new(),
         };
 
         b.pieces[0] =  0b0000000000000000000000000000000000000000000000001111111100000000; //wp 0
@@ -67,8 +68,6 @@ impl Board {
     pub fn new_with_hash(tt: &TTable) -> Board {
         let mut board = Board::new();
         board.hash = board.get_hash(tt);
-        
-        board.add_prev_move();
 
         board
     }
@@ -85,7 +84,8 @@ impl Board {
             halfmove: 0,
             fullmove: 0,
             hash: 0,
-            prev_moves: [0; PREV_MOVE_SIZE],
+            hash_history: Vec::new(),
+            null_moves: Vec::new(),
         };
         let fen: Vec<&str> = fen.split(' ').collect();
 
@@ -191,13 +191,15 @@ impl Board {
             }
         }
 
-        b.halfmove = fen[4].parse().unwrap();
-        b.fullmove = fen[5].parse().unwrap();
+        // some guis leave off the move counters
+        b.halfmove = fen.get(4).and_then(|h| h.parse().ok()).unwrap_or(0);
+        b.fullmove = fen.get(5).and_then(|f| f.parse().ok()).unwrap_or(1);
 
         b
     }
 
     pub fn make(&mut self, m: &Move, tt: &TTable) {
+        self.hash_history.push(self.hash);
         let from_to = SQUARES[m.from as usize] | SQUARES[m.to as usize];
 
         self.pieces[m.piece as usize] ^= from_to;
@@ -362,12 +364,10 @@ impl Board {
         self.hash ^= tt.zorbist_array[768];
 
         //self.fullmove += self.colour as u8;
-
-        self.add_prev_move();
     }
 
     pub fn unmake(&mut self, m: &Move, tt: &TTable) {
-        self.rm_prev_move();
+        self.hash_history.pop();
 
         //self.fullmove -= self.colour as u8;
         self.halfmove = m.last_halfmove;
@@ -673,23 +673,112 @@ impl Board {
         }
 
         if self.ep < 64 {
-            hash ^= tt.zorbist_array[773+(self.ep - (self.ep / 8)) as usize];
+            hash ^= tt.zorbist_array[773+(self.ep % 8) as usize];
         }
 
         hash
     }
 
-    pub fn add_prev_move(&mut self){
-        self.prev_moves[(self.hash & PREV_MOVE_MASK) as usize] += 1;
+    // passes the turn without moving, returns the ep square needed to undo it
+    pub fn make_null(&mut self, tt: &TTable) -> u8 {
+        // still pushed so positions with the same side to move stay two apart in the history
+        self.hash_history.push(self.hash);
+        self.null_moves.push(self.hash_history.len());
+        let ep = self.ep;
+        if ep < 64 {
+            self.hash ^= tt.zorbist_array[773 + (ep % 8) as usize];
+        }
+        self.ep = 64;
+
+        self.colour ^= 1;
+        self.hash ^= tt.zorbist_array[768];
+
+        ep
+    }
+
+    pub fn unmake_null(&mut self, ep: u8, tt: &TTable) {
+        self.hash_history.pop();
+        self.null_moves.pop();
+        self.colour ^= 1;
+        self.hash ^= tt.zorbist_array[768];
+
+        if ep < 64 {
+            self.hash ^= tt.zorbist_array[773 + (ep % 8) as usize];
+        }
+        self.ep = ep;
+    }
+
+    // true if the move into this position was a null move, nothing has been pushed onto the history since
+    pub fn last_move_null(&self) -> bool {
+        self.null_moves.last() == Some(&self.hash_history.len())
+    }
+
+    // true if the side to move has anything other than pawns and its king
+    pub fn has_non_pawn_material(&self) -> bool {
+        self.util[self.colour] != self.pieces[self.colour] | self.pieces[10 + self.colour]
+    }
+
+    // true if this position is drawn by repetition or the fifty move rule, ply is how far it is from the search root
+    pub fn is_draw(&mut self, ply: usize) -> bool {
+        self.is_repetition(ply) || self.is_fifty_moves() || self.is_insufficient_material()
+    }
+
+    // neither side can ever mate, only kings and a single minor piece or bishops that all share a square colour
+    pub fn is_insufficient_material(&self) -> bool {
+        let pawns_and_majors = self.pieces[0] | self.pieces[1] | self.pieces[4] | self.pieces[5] | self.pieces[8] | self.pieces[9];
+        if pawns_and_majors > 0 {
+            return false;
+        }
+
+        let knights = self.pieces[2] | self.pieces[3];
+        let bishops = self.pieces[6] | self.pieces[7];
+
+        (knights | bishops).count_ones() <= 1 
+            || (knights == 0 && (bishops & LIGHT_SQUARES == 0 || bishops & !LIGHT_SQUARES == 0))
     }
 
-    pub fn rm_prev_move(&mut self){
-        self.prev_moves[(self.hash & PREV_MOVE_MASK) as usize] -= 1;
+    // only positions since the last capture or pawn move can repeat, and only every other one has the same side to move.
+    // repeating once inside the search tree is a draw as the same moves could just be played again, 
+    // positions from before the root have to have happened twice already
+    pub fn is_repetition(&self, ply: usize) -> bool {
+        let mut count = 0;
+        // positions from before a null move werent really reached by the moves since, and halfmove isnt reset by one
+        let since_null = self.hash_history.len() - self.null_moves.last().copied().unwrap_or(0);
+
+        for (plies_back, hash) in self.hash_history.iter().rev().enumerate().map(|(i, h)| (i + 1, h))
+            .take((self.halfmove as usize).min(since_null))
+            .skip(1)
+            .step_by(2)
+        {
+            if *hash == self.hash {
+                if plies_back <= ply {
+                    return true;
+                }
+
+                count += 1;
+                if count == 2 {
+                    return true;
+                }
+            }
+        }
+
+        false
     }
 
-    // returns true if 3 move rep or 50 move rule occurs in this pos 
-    pub fn is_bad_pos(&self) -> bool {
-        self.prev_moves[(self.hash & PREV_MOVE_MASK) as usize] == 3 || self.halfmove >= 100
+    // the fifty move rule cant be claimed if the last move delivered mate
+    fn is_fifty_moves(&mut self) -> bool {
+        if self.halfmove < 100 {
+            return false;
+        } else if movegen::in_check_now(self) == 0 {
+            return true;
+        }
+
+        movegen::gen_moves(self).iter().any(|m| {
+            self.make_no_hashing(m);
+            let legal = movegen::in_check_next(self) == 0;
+            self.unmake_no_hashing(m);
+            legal
+        })
     }
 }
 
@@ -702,44 +791,44 @@ impl fmt::Display for Board {
             let s = i.to_string();
             out.push_str(&s);
             out.push_str("    ");
-            for j in i * 8 - 8..i * 8 {
-                if (SQUARES[j] & self.pieces[0]) > 0 {
+            for &sq in &SQUARES[i * 8 - 8..i * 8] {
+                if (sq & self.pieces[0]) > 0 {
                     out.push_str("P ");
                 }
-                if (SQUARES[j] & self.pieces[1]) > 0 {
+                if (sq & self.pieces[1]) > 0 {
                     out.push_str("p ");
                 }
-                if (SQUARES[j] & self.pieces[2]) > 0 {
+                if (sq & self.pieces[2]) > 0 {
                     out.push_str("N ");
                 }
-                if (SQUARES[j] & self.pieces[3]) > 0 {
+                if (sq & self.pieces[3]) > 0 {
                     out.push_str("n ");
                 }
-                if (SQUARES[j] & self.pieces[4]) > 0 {
+                if (sq & self.pieces[4]) > 0 {
                     out.push_str("R ");
                 }
-                if (SQUARES[j] & self.pieces[5]) > 0 {
+                if (sq & self.pieces[5]) > 0 {
                     out.push_str("r ");
                 }
-                if (SQUARES[j] & self.pieces[6]) > 0 {
+                if (sq & self.pieces[6]) > 0 {
                     out.push_str("B ");
                 }
-                if (SQUARES[j] & self.pieces[7]) > 0 {
+                if (sq & self.pieces[7]) > 0 {
                     out.push_str("b ");
                 }
-                if (SQUARES[j] & self.pieces[8]) > 0 {
+                if (sq & self.pieces[8]) > 0 {
                     out.push_str("Q ");
                 }
-                if (SQUARES[j] & self.pieces[9]) > 0 {
+                if (sq & self.pieces[9]) > 0 {
                     out.push_str("q ");
                 }
-                if (SQUARES[j] & self.pieces[10]) > 0 {
+                if (sq & self.pieces[10]) > 0 {
                     out.push_str("K ");
                 }
-                if (SQUARES[j] & self.pieces[11]) > 0 {
+                if (sq & self.pieces[11]) > 0 {
                     out.push_str("k ");
                 }
-                if (SQUARES[j] & self.util[2]) == 0 {
+                if (sq & self.util[2]) == 0 {
                     out.push_str("- ");
                 }
             }
@@ -749,3 +838,77 @@ impl fmt::Display for Board {
         write!(f, "{}", out)
     }
 }
+
+#[test]
+fn null_move_test() {
+    let tt = TTable::new();
+    let mut b = Board::new_from_fen("rnbqkbnr/ppp1pppp/8/8/3pP3/5N2/PPPP1PPP/RNBQKB1R b KQkq e3 0 3");
+    b.hash = b.get_hash(&tt);
+    let og_hash = b.hash;
+
+    let ep = b.make_null(&tt);
+    assert_eq!((b.colour, b.ep), (0, 64));
+    assert_eq!(b.hash, b.get_hash(&tt));
+    assert!(b.last_move_null());
+
+    b.unmake_null(ep, &tt);
+    assert_eq!((b.colour, b.ep), (1, 20));
+    assert!(!b.last_move_null());
+    assert_eq!(b.hash, og_hash);
+
+    // the king triangulating around a null move gets back to the same position with the same side to move,
+    // but that isnt a repetition as the null move couldnt really have been played
+    let mut b = Board::new_from_fen("4k3/8/8/8/8/8/8/4K2R w - - 0 1");
+    b.hash = b.get_hash(&tt);
+    for m in ["h1h2", "e8f8", "h2h1", "f8e8", "e1d1", "e8d8", "d1d2"] {
+        let m = Move::new_from_text(m, &b);
+        b.make(&m, &tt);
+    }
+    let ep = b.make_null(&tt);
+    let mut made = Vec::new();
+    for m in ["d2e1", "d8e8"] {
+        let m = Move::new_from_text(m, &b);
+        b.make(&m, &tt);
+        made.push(m);
+    }
+    assert!(!b.last_move_null());
+    assert!(!b.is_repetition(6));
+
+    // once the null move is undone positions from before it count again
+    for m in made.iter().rev() {
+        b.unmake(m, &tt);
+    }
+    b.unmake_null(ep, &tt);
+    for m in ["d8e8", "d2d1", "e8d8"] {
+        let m = Move::new_from_text(m, &b);
+        b.make(&m, &tt);
+    }
+    assert!(b.is_repetition(4));
+}
+
+#[test]
+fn draw_test() {
+    let tt = TTable::new();
+    let mut b = Board::new_with_hash(&tt);
+    let shuffle = ["g1f3", "g8f6", "f3g1", "f6g8"];
+
+    for m in shuffle {
+        let m = Move::new_from_text(m, &b);
+        b.make(&m, &tt);
+    }
+    // back at the start, a draw if it happened inside the search but not yet in the game
+    assert!(b.is_draw(4));
+    assert!(!b.is_draw(0));
+
+    for m in shuffle {
+        let m = Move::new_from_text(m, &b);
+        b.make(&m, &tt);
+    }
+    assert!(b.is_draw(0));
+
+    // the fifty move rule doesnt apply when the last move was mate
+    let mut b = Board::new_from_fen("k7/1Q6/1K6/8/8/8/8/8 b - - 100 80");
+    assert!(!b.is_draw(0));
+    let mut b = Board::new_from_fen("k7/2Q5/1K6/8/8/8/8/8 b - - 100 80");
+    assert!(b.is_draw(0));
+}
@@ -706,6 +706,9 @@ pub const R8: u64 = 0xFF00000000000000;
 
 pub const RANKS : [u64; 8] = [ R1, R2, R3, R4, R5, R6, R7, R8 ];
 
+// b1, d1 ... the squares a light squared bishop moves on
+pub const LIGHT_SQUARES: u64 = 0x55AA55AA55AA55AA;
+
 pub const SQ_NAMES: [&str; 64] = [
     "a1", "b1", "c1", "d1", "e1", "f1", "g1", "h1", "a2", "b2", "c2", "d2", "e2", "f2", "g2", "h2",
     "a3", "b3", "c3", "d3", "e3", "f3", "g3", "h3", "a4", "b4", "c4", "d4", "e4", "f4", "g4", "h4",
@@ -1,12 +1,13 @@
 use std::borrow::Borrow;
 use std::panic;
 
-use crate::board_info::{SQUARES, SQ_DISTANCE, self, FA, FB, FILES, FH, FG, FC, FD, FE, FF, R1, R8};
-use crate::search::{Search, MAX_SEARCH_DEPTH};
+use crate::board_info::{SQUARES, SQ_DISTANCE, self, FA, FB, FILES, FH, FG, FC, FD, FE, FF, R1, R8, LIGHT_SQUARES};
+use crate::search::{Search, MAX_SEARCH_DEPTH, MAX_PLY};
 use crate::{ Board, Move, TTable };
 use crate::moves::MoveType;
 use crate::movegen::{self, bitscn_fw, in_check_now, print_bb};
-use crate::move_ordering::{MoveOrderList, KillerMoves};
+use crate::move_ordering::MoveOrderList;
+use crate::transposition_table::{ NodeType, TEntry };
 
 const PAWN: i32 = 100;
 const KNIGHT: i32 = 400;
@@ -26,9 +27,20 @@ pub const PIECE_VALUE: [i32; 12] = [
 
 const BISHOP_PAIR_BONUS: i32 = 200;
 
+// score for being mated at the root, being mated n plies from the root scores CHECKMATE + n
 pub const CHECKMATE: i32 = -10000000;
+// scores at least this far from 0 are forced mates
+pub const MATE_BOUND: i32 = -CHECKMATE - MAX_PLY as i32;
+// tablebase wins score just below mates, winning n plies from the root scores TB_WIN - n
+pub const TB_WIN: i32 = MATE_BOUND - 1;
+pub const TB_WIN_BOUND: i32 = TB_WIN - MAX_PLY as i32;
 pub const STALEMATE: i32 = 0;
 
+// evals get multiplied by a scale out of DRAW_SCALE_NORMAL in endings that are hard to win whatever the material says
+const DRAW_SCALE_NORMAL: i32 = 64;
+const OPPOSITE_BISHOPS_SCALE: i32 = 16;
+const ROOK_VS_BISHOP_SCALE: i32 = 8;
+
 pub const DOUBLED_PAWN_PEN: i32 = 20;
 pub const ISOLATED_PAWN_PEN: i32 = 40;
 pub const INNER_LEVER_BONUS: i32 = 25;
@@ -234,72 +246,100 @@ const PIECE_DISTANCE: [i32; 5] = [
 ];
 
 
-pub fn quiesce(search: &mut Search, mut alpha: i32, beta: i32, mate_dist: i32, player: i32) -> i32 {
-    let eval = evaluate(&mut search.board, player);
-    
-    if eval >= beta {
+// a capture has to be able to get this close to alpha on top of the piece it takes to be worth searching
+const DELTA_MARGIN: i32 = 200;
+
+// searches captures until the position is quiet enough for the static eval to be trusted, plus quiet checks on 
+// the first ply. in check there is no standing pat so every evasion gets searched
+pub fn quiesce(search: &mut Search, mut alpha: i32, beta: i32, ply: usize, qply: usize, player: i32) -> i32 {
+    search.nodes += 1;
+    search.seldepth = search.seldepth.max(ply);
+
+    if search.stop_search() {
+        return alpha;
+    } else if search.board.is_draw(ply) {
+        return STALEMATE;
+    } else if ply >= MAX_PLY - 1 {
+        return evaluate(&mut search.board, player);
+    }
+
+    if let Some(hash_score) = search.probe_tt(0, ply, alpha, beta) {
+        return hash_score;
+    }
+
+    let in_check = in_check_now(&search.board) > 0;
+    let stand_pat = if in_check { CHECKMATE + ply as i32 } else { evaluate(&mut search.board, player) };
+
+    if stand_pat >= beta {
+        search.tt.insert(TEntry::new(search.board.hash, None, 0, beta, NodeType::Beta, ply));
         return beta;
     }
-    
-    if alpha < eval {
-        alpha = eval;
+
+    let mut node_type = NodeType::Alpha;
+    if alpha < stand_pat {
+        node_type = NodeType::Pv;
+        alpha = stand_pat;
     }
 
-    let q_narrow = |search: &mut Search| {
+    let tt_move = search.tt.get_bestmove(&search.board);
+    let moves = if in_check {
+        let evasions = movegen::gen_evasions(&search.board);
+        MoveOrderList::new_quiesce_in_check(&mut search.board, &evasions, tt_move, &search.history)
+    } else {
         let attks = movegen::gen_attk(&search.board);
-        MoveOrderList::new_quiesce(&mut search.board, &attks, search.tt)
+        MoveOrderList::new_quiesce(&mut search.board, &attks, tt_move)
     };
-    let q_research = |search: &mut Search| {
-        let moves = movegen::gen_moves(&search.board);
-        MoveOrderList::new_quiesce_in_check(&mut search.board, &moves, search.tt)
+    // quiet checks are only worth it right at the horizon, deeper down they would never end
+    let checks = if !in_check && qply == 0 {
+        let quiet = movegen::gen_quiet(&search.board);
+        MoveOrderList::new_quiesce_checks(&mut search.board, &quiet, &search.history)
+    } else {
+        MoveOrderList { move_scores: Vec::new() }
     };
-    
-    let moves = [q_narrow, q_research];
+
+    let mut best_move = None;
     let mut no_moves = true;
-    let mut checkmate = false;
-    let mut score;
-    
-    for moveset in moves {
-        let move_list = moveset(search);
-    
-        for m in move_list {
-            search.board.make_no_hashing(&m);
-
-            if movegen::in_check_next(&search.board) > 0 {
-                search.board.unmake_no_hashing(&m);
-                checkmate = true;
-                continue;
-            } else if search.board.is_bad_pos() {
-                search.board.unmake_no_hashing(&m);
-                continue;
-            } else {
-                no_moves = false;
-                
-            }
-
-            score = -quiesce(search, -beta, -alpha, mate_dist-1, -player);
-            
-            search.board.unmake_no_hashing(&m);
 
-            if score >= beta {
-                return beta;
-            }
-            if score > alpha {
-                alpha = score;
-            }
+    for m in moves.chain(checks) {
+        // even winning the piece outright wouldnt get back up to alpha
+        if !in_check && m.promo_piece == 12 && m.xpiece < 12 && stand_pat + PIECE_VALUE[m.xpiece as usize] + DELTA_MARGIN <= alpha {
+            continue;
+        }
+
+        search.board.make(&m, search.tt);
 
+        if movegen::in_check_next(&search.board) > 0 {
+            search.board.unmake(&m, search.tt);
+            continue;
         }
+        no_moves = false;
+
+        let score = -quiesce(search, -beta, -alpha, ply + 1, qply + 1, -player);
 
-        if !(checkmate && no_moves) {
-            break;
+        search.board.unmake(&m, search.tt);
+
+        if search.stop_search() {
+            return alpha;
+        }
+
+        if score >= beta {
+            search.tt.insert(TEntry::new(search.board.hash, Some(m), 0, beta, NodeType::Beta, ply));
+            return beta;
+        }
+        if score > alpha {
+            node_type = NodeType::Pv;
+            best_move = Some(m);
+            alpha = score;
         }
     }
 
-    if no_moves && checkmate {
-        CHECKMATE
-    } else {
-        alpha
+    // every evasion was searched so no legal move means mate, otherwise stalemate isnt looked for here
+    if in_check && no_moves {
+        return CHECKMATE + ply as i32;
     }
+
+    search.tt.insert(TEntry::new(search.board.hash, best_move, 0, alpha, node_type, ply));
+    alpha
 }
 
 
@@ -312,6 +352,7 @@ pub fn evaluate(b: &mut Board, player: i32) -> i32 {
     eval += mobility(b);
     eval += pawn_structure(b);
     //eval += king_saftey(b);
+    eval = eval * draw_scale(b, eval) / DRAW_SCALE_NORMAL;
     eval *= player;
         
     eval
@@ -319,6 +360,53 @@ pub fn evaluate(b: &mut Board, player: i32) -> i32 {
     
 }
 
+// how much of the eval the side ahead can actually expect to turn into a win
+fn draw_scale(b: &Board, eval: i32) -> i32 {
+    if b.is_insufficient_material() {
+        return 0;
+    }
+
+    let strong = if eval >= 0 { 0 } else { 1 };
+    let weak = strong ^ 1;
+    let pawns = b.pieces[0] | b.pieces[1];
+    let minors_and_majors = |c: usize| [2, 4, 6, 8].iter().fold(0, |bb, p| bb | b.pieces[p + c]);
+
+    // with one bishop each on different colours neither side can contest the others squares
+    let bishops = b.pieces[6] | b.pieces[7];
+    if b.pieces[6].count_ones() == 1 && b.pieces[7].count_ones() == 1 
+        && minors_and_majors(0) | minors_and_majors(1) == bishops 
+        && bishops & LIGHT_SQUARES != 0 && bishops & !LIGHT_SQUARES != 0 
+    {
+        return OPPOSITE_BISHOPS_SCALE;
+    }
+
+    // kr v kb without pawns is almost always held
+    if pawns == 0 && minors_and_majors(strong) == b.pieces[4 + strong] && b.pieces[4 + strong].count_ones() == 1 
+        && minors_and_majors(weak) == b.pieces[6 + weak] && b.pieces[6 + weak].count_ones() == 1 
+    {
+        return ROOK_VS_BISHOP_SCALE;
+    }
+
+    // rook pawns with a bishop that cant cover the queening square, the defending king just sits in the corner
+    let strong_pawns = b.pieces[strong];
+    if strong_pawns != 0 && b.pieces[weak] == 0 && minors_and_majors(weak) == 0 
+        && minors_and_majors(strong) == b.pieces[6 + strong] 
+        && (strong_pawns & !FA == 0 || strong_pawns & !FH == 0) 
+    {
+        let file = if strong_pawns & FA != 0 { 0 } else { 7 };
+        let queening_sq = if strong == 0 { 56 + file } else { file };
+        let queening_colour = if SQUARES[queening_sq] & LIGHT_SQUARES != 0 { LIGHT_SQUARES } else { !LIGHT_SQUARES };
+        let weak_king = bitscn_fw(&b.pieces[10 + weak]);
+
+        // closeness of 7 or more means the king is on or next to the square
+        if b.pieces[6 + strong] & queening_colour == 0 && SQ_DISTANCE[weak_king][queening_sq] >= 7 {
+            return 0;
+        }
+    }
+
+    DRAW_SCALE_NORMAL
+}
+
 fn mat_balance(b: &Board) -> i32 {
     let pawns = PAWN * (b.pieces[0].count_ones() as i32 - b.pieces[1].count_ones() as i32);
     let knights = KNIGHT * (b.pieces[2].count_ones() as i32 - b.pieces[3].count_ones() as i32);
@@ -626,6 +714,7 @@ fn king_tropism(b: &Board) -> i32 {
 
 
 #[test]
+#[ignore = "reads target/debug/last_pos.txt, which is only there after dumping a uci position"]
 fn eval_test() {
     let f = std::fs::read("target/debug/last_pos.txt").unwrap();
     let buffer = String::from_utf8_lossy(&f);
@@ -650,3 +739,24 @@ fn eval_test() {
     }
     
 }
+
+#[test]
+fn draw_scale_test() {
+    // kb v k and same coloured bishops can never be won
+    assert!(Board::new_from_fen("8/8/4k3/8/8/2B5/8/4K3 w - - 0 1").is_insufficient_material());
+    assert!(Board::new_from_fen("8/8/4k3/8/1b6/2B5/8/4K3 w - - 0 1").is_insufficient_material());
+    assert!(!Board::new_from_fen("8/8/4k3/8/2b5/2B5/8/4K3 w - - 0 1").is_insufficient_material());
+    assert!(!Board::new_from_fen("8/8/4k3/8/8/2N5/7P/4K3 w - - 0 1").is_insufficient_material());
+
+    // wrong bishop for the h pawn with the king in the corner
+    let mut b = Board::new_from_fen("7k/8/8/8/8/3B4/7P/4K3 w - - 0 1");
+    assert_eq!(evaluate(&mut b, 1), 0);
+    // the right bishop wins
+    let mut b = Board::new_from_fen("7k/8/8/8/8/2B5/7P/4K3 w - - 0 1");
+    assert!(evaluate(&mut b, 1) > 300);
+
+    // opposite coloured bishops a pawn up is much closer to a draw
+    let b = Board::new_from_fen("8/5k2/8/3b4/8/2B5/5PP1/6K1 w - - 0 1");
+    assert_eq!(draw_scale(&b, 100), OPPOSITE_BISHOPS_SCALE);
+    assert_eq!(draw_scale(&Board::new(), 0), DRAW_SCALE_NORMAL);
+}
@@ -4,7 +4,7 @@ use std::sync::{ Arc, RwLock };
 use std::collections::HashMap;
 use std::{fs, panic};
 
-use search::{ Search, TimeControl };
+use search::{ Search, SearchSignals, TimeControl };
 use moves::Move;
 use board::Board;
 use transposition_table::TTable;
@@ -18,9 +18,9 @@ mod moves;
 mod opening_book;
 mod perft;
 mod search;
+mod syzygy;
 mod transposition_table;
 mod uci;
-// mod uci_mt;
 
 fn main() {
     let author = "George";
@@ -87,7 +87,8 @@ fn debug() {
     // // *entry += 1;
     
     // // let og_hash = board.hash;
-    let bestmove = Search::new(board.clone(), &mut tt, TimeControl::new_now()).iterative_deepening_search().unwrap();
+    let signals = SearchSignals::new();
+    let bestmove = Search::new(board.clone(), &tt, TimeControl::new_now(), &signals).iterative_deepening_search()[0];
     println!("\nbestmove: {}\n", bestmove.as_uci_string());
     // // assert_eq!(og_hash, board.hash);
     
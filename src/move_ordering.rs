@@ -2,11 +2,96 @@ use std::cmp::Reverse;
 
 use crate::eval::PIECE_VALUE;
 use crate::moves::MoveType;
+use crate::board_info::SQUARES;
 use crate::{ Board, Move, TTable };
 use crate::movegen::*;
 
 const ATTACK_MOVE_OFFSET: i32 = 100000;
- 
+
+// gravity keeps every history score within +-MAX_HISTORY
+const MAX_HISTORY: i32 = 16384;
+const MAX_HISTORY_BONUS: i32 = 1200;
+
+// history heuristic, indexed by piece and to square
+pub type HistoryTable = [[i32; 64]; 12];
+
+// quiet move ordering stats for one search thread, kept between searches and aged at the start of each one
+pub struct History {
+    butterfly: HistoryTable,
+    // quiet move that refuted each move, indexed by that moves piece and to square
+    counter_moves: [[Option<Move>; 64]; 12],
+    // continuation history, one table per piece and to square of the move 1 and 2 plies back
+    cont: [Vec<HistoryTable>; 2],
+}
+
+impl History {
+    pub fn new() -> History {
+        History {
+            butterfly: [[0; 64]; 12],
+            counter_moves: [[None; 64]; 12],
+            cont: [vec![[[0; 64]; 12]; 12 * 64], vec![[[0; 64]; 12]; 12 * 64]],
+        }
+    }
+
+    // halve everything so the last search still counts but the new one soon takes over
+    pub fn age(&mut self) {
+        let tables = self.cont.iter_mut().flatten().chain(std::iter::once(&mut self.butterfly));
+        for h in tables.flatten().flatten() {
+            *h /= 2;
+        }
+    }
+
+    // prev holds the moves made 1 and 2 plies back, none for null moves or before the root
+    pub fn score(&self, m: &Move, prev: &[Option<Move>; 2]) -> i32 {
+        let mut score = self.butterfly[m.piece as usize][m.to as usize];
+        for (table, p) in self.cont.iter().zip(prev) {
+            if let Some(p) = p {
+                score += table[cont_index(p)][m.piece as usize][m.to as usize];
+            }
+        }
+
+        score
+    }
+
+    pub fn counter_move(&self, prev: Option<Move>) -> Option<Move> {
+        prev.and_then(|p| self.counter_moves[p.piece as usize][p.to as usize])
+    }
+
+    // rewards the quiet move that caused a beta cutoff and punishes the quiets searched before it
+    pub fn update(&mut self, best: &Move, tried: &[Move], prev: &[Option<Move>; 2], depth: usize) {
+        let bonus = (16 * (depth * depth) as i32).min(MAX_HISTORY_BONUS);
+
+        self.add(best, prev, bonus);
+        for m in tried.iter().filter(|m| *m != best) {
+            self.add(m, prev, -bonus);
+        }
+
+        if let Some(p) = prev[0] {
+            self.counter_moves[p.piece as usize][p.to as usize] = Some(*best);
+        }
+    }
+
+    fn add(&mut self, m: &Move, prev: &[Option<Move>; 2], bonus: i32) {
+        let (piece, to) = (m.piece as usize, m.to as usize);
+
+        gravity(&mut self.butterfly[piece][to], bonus);
+        for (table, p) in self.cont.iter_mut().zip(prev) {
+            if let Some(p) = p {
+                gravity(&mut table[cont_index(p)][piece][to], bonus);
+            }
+        }
+    }
+}
+
+fn cont_index(m: &Move) -> usize {
+    m.piece as usize * 64 + m.to as usize
+}
+
+// scores that are already large move less, so nothing grows past MAX_HISTORY
+fn gravity(h: &mut i32, bonus: i32) {
+    *h += bonus - *h * bonus.abs() / MAX_HISTORY;
+}
+
 #[derive(Clone, Copy)]
 pub struct KillerMoves {
     km1: Option<Move>,
@@ -19,8 +104,15 @@ impl KillerMoves {
     }
 
     pub fn push(&mut self, m: Move) {
-        self.km2 = self.km1;
-        self.km1 = Some(m);
+        if self.km1 != Some(m) {
+            self.km2 = self.km1;
+            self.km1 = Some(m);
+        }
+    }
+
+    // keeps the best killer in the second slot so it is the first to be replaced
+    pub fn age(&mut self) {
+        self.km2 = self.km1.take();
     }
 
     pub fn contains(&self, m: Move) -> i32 {
@@ -57,6 +149,18 @@ impl RootOrderList {
         self.now = self.next.clone();
     }
 
+    // drops every move ranked below the best, ranks line up with now and are i32::MIN for illegal moves
+    pub fn retain_best(&mut self, ranks: &[i32]) {
+        let best = ranks.iter().copied().max().unwrap_or(i32::MIN);
+        if best == i32::MIN {
+            return;
+        }
+
+        let keep = |moves: &[(Move, i32)]| moves.iter().zip(ranks).filter(|(_, &rank)| rank == best).map(|(m, _)| *m).collect();
+        self.now = keep(&self.now);
+        self.next = keep(&self.next);
+    }
+
     pub fn rescore_move(&mut self, index: usize, new_score: i32){
         self.next[index].1 = new_score;
     }
@@ -75,8 +179,13 @@ impl RootOrderList {
         best_move
     }
 
+    // next is kept in the same order as now so scores from this iteration line up with their moves
     pub fn sort(&mut self) {
-        self.now.sort_by_key(|ms| Reverse(ms.1))
+        let mut order: Vec<usize> = (0..self.now.len()).collect();
+        order.sort_by_key(|&i| Reverse(self.now[i].1));
+
+        self.now = order.iter().map(|&i| self.now[i]).collect();
+        self.next = order.iter().map(|&i| self.next[i]).collect();
     }
 }
 
@@ -90,10 +199,9 @@ impl MoveOrderList {
         self.move_scores.len()
     }
 
-    pub fn new_pv_attacks(b: &mut Board, moves: &[Move], tt: &TTable) -> MoveOrderList {
+    pub fn new_pv_attacks(b: &mut Board, moves: &[Move], bestmove: Option<Move>) -> MoveOrderList {
         let mut move_scores = Vec::with_capacity(moves.len());
-        
-        let bestmove = tt.get_bestmove(b.hash);
+
         let mut added = false;
 
         for m in moves {
@@ -104,32 +212,31 @@ impl MoveOrderList {
                 continue;
             }
              
-            move_scores.push((*m, score_attacks(b, m, tt)));
+            move_scores.push((*m, score_attacks(b, m)));
             // move_scores.push((m, 0));
 
         }
 
-        if let Some(bm) = bestmove {
+        // a quiet tt move still gets tried first, new_quiet then skips it
+        if let (Some(bm), false) = (bestmove, added) {
             move_scores.push((bm, i32::MAX));
-
         }
 
         MoveOrderList { move_scores }
     }
 
-    pub fn new_quiet(b: &Board, moves: &[Move], km: &KillerMoves, tt: &TTable) -> MoveOrderList {
+    pub fn new_quiet(moves: &[Move], km: &KillerMoves, bestmove: Option<Move>, hist: &History, prev: &[Option<Move>; 2]) -> MoveOrderList {
         let mut move_scores: Vec<(Move, i32)> = Vec::with_capacity(moves.len());
-        
-        let bestmove = tt.get_bestmove(b.hash);
+
+        let counter = hist.counter_move(prev[0]);
 
         for m in moves {
-            //add pv to hash if one exists
+            // already searched with the captures
             if bestmove == Some(*m){
-                move_scores.push((*m, i32::MAX));
                 continue;
             }
-             
-            move_scores.push((*m, score_quiet(b, m, km, tt)));
+
+            move_scores.push((*m, score_quiet(m, km, counter, hist, prev)));
             // move_scores.push((m, 0));
 
         }
@@ -138,41 +245,56 @@ impl MoveOrderList {
     }
 
 
-    pub fn new_quiesce(b: &mut Board, moves: &[Move], tt: &TTable) -> MoveOrderList {
+    pub fn new_quiesce(b: &mut Board, moves: &[Move], bestmove: Option<Move>) -> MoveOrderList {
         let mut move_scores = Vec::with_capacity(moves.len());
 
         for m in moves {
-            // add pv to hash if one exists
-            let see_score = score_attacks(b, m, tt);
-            if see_score >= 0{
-                move_scores.push((*m, see_score));    
+            if bestmove == Some(*m) {
+                move_scores.push((*m, i32::MAX));
+            // losing captures arent worth looking at in quiesce
+            } else if see_ge(b, m, 0) {
+                move_scores.push((*m, score_attacks(b, m)));    
             }
-            // move_scores.push((m, 0));
         }
 
-        
         MoveOrderList { move_scores }
     }
     
-    pub fn new_quiesce_in_check(b: &mut Board, moves: &[Move], tt: &TTable) -> MoveOrderList {
+    // every evasion has to be searched, captures go first and then the quiet moves by history
+    pub fn new_quiesce_in_check(b: &mut Board, moves: &[Move], bestmove: Option<Move>, hist: &History) -> MoveOrderList {
         let mut move_scores = Vec::with_capacity(moves.len());
 
         for m in moves {
-            // add pv to hash if one exists
+            if bestmove == Some(*m) {
+                move_scores.push((*m, i32::MAX));
+                continue;
+            }
+
             match m.move_type {
                 MoveType::Capture | MoveType::EpCapture | MoveType::PromoCapture => {
-                    let see_score = score_attacks(b, m, tt);
-                    if see_score < 0 {
-                        move_scores.push((*m, see_score));
-                    }
+                    move_scores.push((*m, score_attacks(b, m)));
                 },
                 
-                _ => move_scores.push((*m, tt.get_hh(m.piece, m.to))),
+                _ => move_scores.push((*m, hist.score(m, &[None, None]))),
             }
         }
         
         MoveOrderList { move_scores }
     }
+
+    // quiet checks for the first quiesce ply
+    pub fn new_quiesce_checks(b: &mut Board, moves: &[Move], hist: &History) -> MoveOrderList {
+        let mut move_scores = Vec::with_capacity(moves.len());
+
+        for m in moves {
+            // checks that just hang the piece wont lead anywhere
+            if m.promo_piece == 12 && gives_check(b, m) && see_ge(b, m, 0) {
+                move_scores.push((*m, hist.score(m, &[None, None])));
+            }
+        }
+
+        MoveOrderList { move_scores }
+    }
 }
 
 
@@ -203,9 +325,9 @@ impl Iterator for MoveOrderList {
     }
 }
 
-fn score_move(b: &Board, m: &Move, tt: &TTable) -> i32 {
+fn score_move(b: &Board, m: &Move, tt: &TTable, hh: &HistoryTable) -> i32 {
     // if hashtable bestmove
-    if let Some(bestmove) = tt.get_bestmove(b.hash) {
+    if let Some(bestmove) = tt.get_bestmove(b) {
         if bestmove == *m {
             return i32::MAX;
         }
@@ -216,51 +338,129 @@ fn score_move(b: &Board, m: &Move, tt: &TTable) -> i32 {
         m.xpiece as i32 - m.piece as i32 + 100000
     // if quiet move see if the to square is beneficial or not
     } else {
-        tt.get_hh(m.piece, m.to)
+        hh[m.piece as usize][m.to as usize]
     }
 }
 
-fn score_attacks(b: &mut Board, m: &Move, tt: &TTable) -> i32 {
-    static_exchange_eval(b, m) + ATTACK_MOVE_OFFSET
+fn score_attacks(b: &Board, m: &Move) -> i32 {
+    see(b, m) + ATTACK_MOVE_OFFSET
 }
 
-fn score_quiet(b: &Board, m: &Move, km: &KillerMoves, tt: &TTable) -> i32 {
+fn score_quiet(m: &Move, km: &KillerMoves, counter: Option<Move>, hist: &History, prev: &[Option<Move>; 2]) -> i32 {
     let is_km = km.contains(*m);
     if is_km > 0 {
         // offset killer moves so they score better than bad captures
-        ATTACK_MOVE_OFFSET + is_km 
+        ATTACK_MOVE_OFFSET + is_km
+    } else if counter == Some(*m) {
+        // counter move comes straight after the killers
+        ATTACK_MOVE_OFFSET
     } else {
-        tt.get_hh(m.piece, m.to)
+        hist.score(m, prev)
     }
 }
 
 
-fn static_exchange_eval(b: &mut Board, m: &Move) -> i32 {
-    let mut value = 0;
-    
-    b.make_no_hashing(m);
-    value = PIECE_VALUE[m.xpiece as usize] - see(b, m.to);
-    b.unmake_no_hashing(m);
-
-    value
+// true if the exchange started by m on its to square wins at least threshold
+pub fn see_ge(b: &Board, m: &Move, threshold: i32) -> bool {
+    see(b, m) >= threshold
 }
 
-fn see(b: &mut Board, to: u8) -> i32 {
-    let mut value = 0;
-    let mut smallest: (Option<Move>, i32) = (None, PIECE_VALUE[11]);
+// swap list static exchange evaluation, both sides keep recapturing on the to square with their least valuable 
+// attacker and can stop whenever carrying on would lose material
+pub fn see(b: &Board, m: &Move) -> i32 {
+    let to = m.to as usize;
+    let mut gain = [0; 32];
+    let mut occ = b.util[2] ^ SQUARES[m.from as usize];
+
+    gain[0] = if m.xpiece < 12 { PIECE_VALUE[m.xpiece as usize] } else { 0 };
+    let mut on_sq = m.piece as usize;
+    if m.promo_piece < 12 {
+        gain[0] += PIECE_VALUE[m.promo_piece as usize] - PIECE_VALUE[m.piece as usize];
+        on_sq = m.promo_piece as usize;
+    }
+    if m.move_type == MoveType::EpCapture {
+        // captured pawn is behind the to square
+        occ ^= SQUARES[if b.colour == 0 { to - 8 } else { to + 8 }];
+    }
 
-    for m in gen_attk(b){
-        if m.to == to && PIECE_VALUE[m.xpiece as usize] < smallest.1 {
-            smallest = (Some(m), PIECE_VALUE[m.xpiece as usize]);
+    // attackers of both colours, the ones used up get removed from occ which uncovers any x-rays behind them
+    let mut attackers = attacks_to_occ(b, to, 0, occ) | attacks_to_occ(b, to, 1, occ);
+    let mut colour = b.colour ^ 1;
+    let mut d = 0;
+
+    loop {
+        d += 1;
+        // score if the piece on the square gets taken
+        gain[d] = PIECE_VALUE[on_sq] - gain[d - 1];
+        if (-gain[d - 1]).max(gain[d]) < 0 || d == gain.len() - 1 {
+            break;
         }
+
+        let Some((from, piece)) = least_valuable_attacker(b, attackers & b.util[colour], colour) else {
+            break;
+        };
+
+        occ ^= from;
+        attackers = (attackers | attacks_to_occ(b, to, 0, occ) | attacks_to_occ(b, to, 1, occ)) & occ;
+        on_sq = piece;
+        colour ^= 1;
     }
 
-    if smallest.1 < PIECE_VALUE[11] {
-        let m = smallest.0.unwrap();
-        b.make_no_hashing(&m);
-        value = PIECE_VALUE[m.xpiece as usize] - see(b, to);
-        b.unmake_no_hashing(&m);
+    while d > 1 {
+        d -= 1;
+        gain[d - 1] = -(-gain[d - 1]).max(gain[d]);
     }
 
-    value
-} 
+    gain[0]
+}
+
+// bishops are worth less than knights here
+const SEE_ORDER: [usize; 6] = [0, 6, 2, 4, 8, 10];
+
+fn least_valuable_attacker(b: &Board, attackers: u64, colour: usize) -> Option<(u64, usize)> {
+    SEE_ORDER.iter()
+        .map(|p| p + colour)
+        .find(|&p| attackers & b.pieces[p] > 0)
+        .map(|p| {
+            let from = attackers & b.pieces[p];
+            (from & from.wrapping_neg(), p)
+        })
+}
+
+#[test]
+fn see_test() {
+    // undefended pawn
+    let b = Board::new_from_fen("1k1r4/1pp4p/p7/4p3/8/P5P1/1PP4P/2K1R3 w - - 0 1");
+    let m = Move::new_from_text("e1e5", &b);
+    assert_eq!(see(&b, &m), 100);
+
+    // knight takes a defended pawn, both queens only join in as x-rays from behind the rook and bishop
+    let b = Board::new_from_fen("1k1r3q/1ppn3p/p4b2/4p3/8/P2N2P1/1PP1R1BP/2K1Q3 w - - 0 1");
+    let m = Move::new_from_text("d3e5", &b);
+    assert_eq!(see(&b, &m), 100 - 400);
+    assert!(see_ge(&b, &m, -300));
+    assert!(!see_ge(&b, &m, -299));
+}
+
+#[test]
+fn history_test() {
+    let b = Board::new();
+    let prev = Move::new_from_text("e2e4", &b);
+    let good = Move::new_from_text("g1f3", &b);
+    let bad = Move::new_from_text("b1c3", &b);
+    let mut hist = History::new();
+
+    for _ in 0..1000 {
+        hist.update(&good, &[bad, good], &[Some(prev), None], 20);
+    }
+
+    // gravity keeps the scores bounded however often a move cuts off
+    assert!(hist.score(&good, &[Some(prev), None]) <= 2 * MAX_HISTORY);
+    assert!(hist.score(&bad, &[Some(prev), None]) >= -2 * MAX_HISTORY);
+    assert!(hist.score(&good, &[None, None]) > hist.score(&bad, &[None, None]));
+    assert_eq!(hist.counter_move(Some(prev)), Some(good));
+
+    let before = hist.score(&good, &[None, None]);
+    hist.age();
+    assert_eq!(hist.score(&good, &[None, None]), before / 2);
+}
@@ -27,10 +27,124 @@ pub fn gen_moves(b: &Board) -> Vec<Move> {
     let mut moves = Vec::with_capacity(MAX_MOVES);
     all_attk(&mut moves, b);
     all_quiet(&mut moves, b);
-    
+
+    moves
+}
+
+// moves that could get out of check, the king stepping away or another piece capturing or blocking the checker.
+// like the other generators these are pseudo legal so still need an in_check_next test
+pub fn gen_evasions(b: &Board) -> Vec<Move> {
+    let mut moves = Vec::with_capacity(MAX_CAPTURES);
+    add_king_attk(&mut moves, b);
+    add_king_quiet(&mut moves, b);
+
+    // only the king can move out of a double check
+    let checkers = in_check_now(b);
+    if checkers.count_ones() > 1 {
+        return moves;
+    }
+
+    let king_sq = bitscn_fw(&b.pieces[10 + b.colour]);
+    let block = between(king_sq, bitscn_fw(&checkers), b.util[2]);
+
+    add_pawn_evasions(&mut moves, b, checkers, block);
+    for piece in [2, 4, 6, 8] {
+        add_piece_evasions(&mut moves, b, piece + b.colour, checkers, block);
+    }
     moves
 }
 
+// knight, rook, bishop and queen moves that take the checker or land in between it and the king
+fn add_piece_evasions(moves: &mut Vec<Move>, b: &Board, piece: usize, checkers: u64, block: u64) {
+    let checker_sq = bitscn_fw(&checkers);
+
+    let mut pieces = b.pieces[piece];
+    while pieces > 0 {
+        let from = bitscn_fw(&pieces);
+        let m = match piece / 2 {
+            1 => knight_moves(from),
+            2 => rook_moves(from, b.util[2]),
+            3 => bishop_moves(from, b.util[2]),
+            _ => queen_moves(from, b.util[2]),
+        };
+
+        if m & checkers > 0 {
+            moves.push(Move::new_capture(from as u8, checker_sq as u8, piece as u8, get_xpiece(checker_sq, b) as u8, b.ep, b));
+        }
+
+        let mut quiet = m & block;
+        while quiet > 0 {
+            let to = bitscn_fw(&quiet);
+            moves.push(Move::new_quiet(from as u8, to as u8, piece as u8, b.ep, b));
+            quiet &= quiet - 1;
+        }
+        pieces &= pieces - 1;
+    }
+}
+
+fn add_pawn_evasions(moves: &mut Vec<Move>, b: &Board, checkers: u64, block: u64) {
+    let colour = b.colour;
+    let (promos, promo_rank) = if colour == 0 { ([8, 4, 2, 6], R8) } else { ([9, 5, 3, 7], R1) };
+    // an ep capture removes a checking pawn without landing on its square
+    let ep_pawn = if b.ep < 64 { SQUARES[b.ep as usize - 8 + colour * 16] } else { 0 };
+    let ep_target = if ep_pawn & checkers > 0 { SQUARES[b.ep as usize] } else { 0 };
+
+    let mut pawns = b.pieces[colour];
+    while pawns > 0 {
+        let from = bitscn_fw(&pawns);
+        let (attk, push) = if colour == 0 {
+            (wpawn_attk(from, b), wpawn_moves(from, b))
+        } else {
+            (bpawn_attk(from, b), bpawn_moves(from, b))
+        };
+
+        let mut attk = attk & (checkers | ep_target);
+        while attk > 0 {
+            let to = bitscn_fw(&attk);
+            if SQUARES[to] & promo_rank > 0 {
+                for i in promos {
+                    moves.push(Move::new_promo_capture(from as u8, to as u8, colour as u8, get_xpiece(to, b) as u8, b.ep, b, i));
+                }
+            } else if to as u8 == b.ep {
+                moves.push(Move::new_ep_capture(from as u8, to as u8, colour as u8, (1 - colour) as u8, b.ep, b));
+            } else {
+                moves.push(Move::new_capture(from as u8, to as u8, colour as u8, get_xpiece(to, b) as u8, b.ep, b));
+            }
+            attk &= attk - 1;
+        }
+
+        let mut push = push & block;
+        while push > 0 {
+            let to = bitscn_fw(&push);
+            if SQUARES[to] & promo_rank > 0 {
+                for i in promos {
+                    moves.push(Move::new_promo(from as u8, to as u8, colour as u8, b.ep, b, i));
+                }
+            } else if from.abs_diff(to) == 16 {
+                moves.push(Move::new_double_push(from as u8, to as u8, colour as u8, b.ep, b));
+            } else {
+                moves.push(Move::new_quiet(from as u8, to as u8, colour as u8, b.ep, b));
+            }
+            push &= push - 1;
+        }
+        pawns &= pawns - 1;
+    }
+}
+
+// squares strictly between two squares on the same line, empty if they dont share one
+fn between(sq1: usize, sq2: usize, occ: u64) -> u64 {
+    let (rank1, file1) = (sq1 / 8, sq1 % 8);
+    let (rank2, file2) = (sq2 / 8, sq2 % 8);
+
+    if rank1 == rank2 || file1 == file2 {
+        rook_moves(sq1, occ) & rook_moves(sq2, occ)
+    } else if rank1.abs_diff(rank2) == file1.abs_diff(file2) {
+        bishop_moves(sq1, occ) & bishop_moves(sq2, occ)
+    } else {
+        0
+    }
+}
+
 fn all_quiet(moves: &mut Vec<Move>, b: &Board) {
     if b.colour == 0 {
         add_wp_quiet(moves, b);
@@ -224,7 +338,7 @@ fn add_rook_quiet(moves: &mut Vec<Move>, b: &Board) {
     let mut rooks = b.pieces[4 + b.colour];
     while rooks > 0 {
         from = bitscn_fw(&rooks);
-        let m = rook_moves(from, b);
+        let m = rook_moves(from, b.util[2]);
         quiet = m & !b.util[2];
 
         while quiet > 0 {
@@ -244,7 +358,7 @@ fn add_rook_attk(moves: &mut Vec<Move>, b: &Board) {
     let mut rooks = b.pieces[4 + b.colour];
     while rooks > 0 {
         from = bitscn_fw(&rooks);
-        let m = rook_moves(from, b);
+        let m = rook_moves(from, b.util[2]);
         attk = m & b.util[1 - b.colour];
 
         while attk > 0 {
@@ -264,7 +378,7 @@ fn add_bishop_quiet(moves: &mut Vec<Move>, b: &Board) {
     let mut bishops = b.pieces[6 + b.colour];
     while bishops > 0 {
         from = bitscn_fw(&bishops);
-        let m = bishop_moves(from, b);
+        let m = bishop_moves(from, b.util[2]);
         quiet = m & !b.util[2];
 
         while quiet > 0 {
@@ -284,7 +398,7 @@ fn add_bishop_attk(moves: &mut Vec<Move>, b: &Board) {
     let mut bishops = b.pieces[6 + b.colour];
     while bishops > 0 {
         from = bitscn_fw(&bishops);
-        let m = bishop_moves(from, b);
+        let m = bishop_moves(from, b.util[2]);
         attk = m & b.util[1 - b.colour];
         while attk > 0 {
             to = bitscn_fw(&attk);
@@ -303,7 +417,7 @@ fn add_queen_quiet(moves: &mut Vec<Move>, b: &Board) {
     let mut queens = b.pieces[8 + b.colour];
     while queens > 0 {
         from = bitscn_fw(&queens);
-        let m = queen_moves(from, b);
+        let m = queen_moves(from, b.util[2]);
         quiet = m & !b.util[2];
 
         while quiet > 0 {
@@ -323,7 +437,7 @@ fn add_queen_attk(moves: &mut Vec<Move>, b: &Board) {
     let mut queens = b.pieces[8 + b.colour];
     while queens > 0 {
         from = bitscn_fw(&queens);
-        let m = queen_moves(from, b);
+        let m = queen_moves(from, b.util[2]);
         attk = m & b.util[1 - b.colour];
 
         while attk > 0 {
@@ -514,48 +628,55 @@ fn bpawn_attk(index: usize, b: &Board) -> u64 {
     (down_left | down_right) & (b.util[0] | SQUARES[b.ep as usize])
 }
 
+// occ is the set of blocking pieces, usually b.util[2]
 #[inline]
-fn pos_ray(dir: usize, sq: usize, b: &Board) -> u64 {
+fn pos_ray(dir: usize, sq: usize, occ: u64) -> u64 {
     let mv = RAYS[dir][sq];
-    let blk = mv & b.util[2];
-    let b_index: usize = bitscn_fw(&blk) as usize;
+    let blk = mv & occ;
+    let b_index: usize = bitscn_fw(&blk);
     mv ^ RAYS[dir][b_index] 
 }
 
 #[inline]
-fn neg_ray(dir: usize, sq: usize, b: &Board) -> u64 {
+fn neg_ray(dir: usize, sq: usize, occ: u64) -> u64 {
     let mv = RAYS[dir][sq];
-    let blk = mv & b.util[2] | 1;
-    let b_index: usize = bitscn_rv(&blk) as usize;
+    let blk = mv & occ | 1;
+    let b_index: usize = bitscn_rv(&blk);
 
     mv ^ (RAYS[dir][b_index])
 }
 
-fn rook_moves(sq: usize, b: &Board) -> u64 {
-    pos_ray(1, sq, b) | pos_ray(3, sq, b) | neg_ray(5, sq, b) | neg_ray(7, sq, b)
+fn rook_moves(sq: usize, occ: u64) -> u64 {
+    pos_ray(1, sq, occ) | pos_ray(3, sq, occ) | neg_ray(5, sq, occ) | neg_ray(7, sq, occ)
 }
 
-fn bishop_moves(sq: usize, b: &Board) -> u64 {
-    pos_ray(0, sq, b) | pos_ray(2, sq, b) | neg_ray(4, sq, b) | neg_ray(6, sq, b)
+fn bishop_moves(sq: usize, occ: u64) -> u64 {
+    pos_ray(0, sq, occ) | pos_ray(2, sq, occ) | neg_ray(4, sq, occ) | neg_ray(6, sq, occ)
 }
 
-fn queen_moves(sq: usize, b: &Board) -> u64 {
-    bishop_moves(sq, b) | rook_moves(sq, b)
+fn queen_moves(sq: usize, occ: u64) -> u64 {
+    bishop_moves(sq, occ) | rook_moves(sq, occ)
 }
 
 
 pub fn attacks_to(b: &Board, piece_index: usize, colour: usize) -> u64 {
+    attacks_to_occ(b, piece_index, colour, b.util[2])
+}
+
+// same as attacks_to but only pieces in occ can attack or block, 
+// so sliders behind a piece removed from occ get found as x-ray attackers
+pub fn attacks_to_occ(b: &Board, piece_index: usize, colour: usize, occ: u64) -> u64 {
     let pawn: u64 = if colour == 0 {
         wpawn_attk(piece_index, b) & b.pieces[1]
     } else {
         bpawn_attk(piece_index, b) & b.pieces[0]
     };
 
-    pawn
+    (pawn
     | knight_moves(piece_index) & b.pieces[3 - colour] 
-    | rook_moves(piece_index, b) & (b.pieces[5 - colour] | b.pieces[9 - colour]) 
-    | bishop_moves(piece_index, b) & (b.pieces[7 - colour] | b.pieces[9 - colour]) 
-    | king_moves(piece_index) & (b.pieces[11 - colour])
+    | rook_moves(piece_index, occ) & (b.pieces[5 - colour] | b.pieces[9 - colour]) 
+    | bishop_moves(piece_index, occ) & (b.pieces[7 - colour] | b.pieces[9 - colour]) 
+    | king_moves(piece_index) & (b.pieces[11 - colour])) & occ
 }
 
 pub fn in_check_next(b: &Board) -> u64 {
@@ -571,6 +692,50 @@ pub fn in_check_now(b: &Board) -> u64 {
     attacks_to(b, k_index, b.colour)
 }
 
+// whether m checks the enemy king without making it, either from the square it lands on
+// or by moving out of the way of one of our sliders
+pub fn gives_check(b: &Board, m: &Move) -> bool {
+    let us = b.colour;
+    let k_index = bitscn_fw(&b.pieces[11 - us]);
+    let king = SQUARES[k_index];
+    let from = SQUARES[m.from as usize];
+    let mut occ = (b.util[2] ^ from) | SQUARES[m.to as usize];
+    let mut moved = from;
+
+    // castling can only check with the rook
+    let (piece, sq) = match m.move_type {
+        MoveType::WKingSide => (4, 5),
+        MoveType::WQueenSide => (4, 3),
+        MoveType::BKingSide => (5, 61),
+        MoveType::BQueenSide => (5, 59),
+        MoveType::Promo | MoveType::PromoCapture => (m.promo_piece as usize, m.to as usize),
+        _ => (m.piece as usize, m.to as usize),
+    };
+    match m.move_type {
+        MoveType::WKingSide | MoveType::WQueenSide | MoveType::BKingSide | MoveType::BQueenSide => {
+            let rook_from = if sq % 8 == 5 { sq + 2 } else { sq - 3 };
+            occ ^= SQUARES[rook_from] | SQUARES[sq];
+            moved |= SQUARES[rook_from];
+        }
+        MoveType::EpCapture => occ ^= SQUARES[m.to as usize - 8 + (us * 16)],
+        _ => (),
+    }
+
+    let direct = match piece / 2 {
+        0 if us == 0 => ((SQUARES[sq] & !FA) << 7) | ((SQUARES[sq] & !FH) << 9),
+        0 => ((SQUARES[sq] & !FH) >> 7) | ((SQUARES[sq] & !FA) >> 9),
+        1 => knight_moves(sq),
+        2 => rook_moves(sq, occ),
+        3 => bishop_moves(sq, occ),
+        4 => queen_moves(sq, occ),
+        _ => 0,
+    };
+    let discovered = rook_moves(k_index, occ) & (b.pieces[4 + us] | b.pieces[8 + us]) & !moved
+        | bishop_moves(k_index, occ) & (b.pieces[6 + us] | b.pieces[8 + us]) & !moved;
+
+    (direct & king | discovered) > 0
+}
+
 pub fn get_xpiece(sq: usize, b: &Board) -> usize {
     let mut square = SQUARES[sq];
     
@@ -620,42 +785,42 @@ pub fn print_bb(m: u64, b: &Board) {
         out.push_str(&s);
         out.push_str("   ");
 
-        for j in i * 8 - 8..i * 8 {
-            if (SQUARES[j] & m) > 0 {
+        for &sq in &SQUARES[i * 8 - 8..i * 8] {
+            if (sq & m) > 0 {
                 out.push('(');
             } else {
                 out.push(' ');
             }
 
-            if (SQUARES[j] & b.pieces[0]) > 0 {
+            if (sq & b.pieces[0]) > 0 {
                 out.push('P');
-            } else if (SQUARES[j] & b.pieces[1]) > 0 {
+            } else if (sq & b.pieces[1]) > 0 {
                 out.push('p');
-            } else if (SQUARES[j] & b.pieces[2]) > 0 {
+            } else if (sq & b.pieces[2]) > 0 {
                 out.push('N');
-            } else if (SQUARES[j] & b.pieces[3]) > 0 {
+            } else if (sq & b.pieces[3]) > 0 {
                 out.push('n');
-            } else if (SQUARES[j] & b.pieces[4]) > 0 {
+            } else if (sq & b.pieces[4]) > 0 {
                 out.push('R');
-            } else if (SQUARES[j] & b.pieces[5]) > 0 {
+            } else if (sq & b.pieces[5]) > 0 {
                 out.push('r');
-            } else if (SQUARES[j] & b.pieces[6]) > 0 {
+            } else if (sq & b.pieces[6]) > 0 {
                 out.push('B');
-            } else if (SQUARES[j] & b.pieces[7]) > 0 {
+            } else if (sq & b.pieces[7]) > 0 {
                 out.push('b');
-            } else if (SQUARES[j] & b.pieces[8]) > 0 {
+            } else if (sq & b.pieces[8]) > 0 {
                 out.push('Q');
-            } else if (SQUARES[j] & b.pieces[9]) > 0 {
+            } else if (sq & b.pieces[9]) > 0 {
                 out.push('q');
-            } else if (SQUARES[j] & b.pieces[10]) > 0 {
+            } else if (sq & b.pieces[10]) > 0 {
                 out.push('K');
-            } else if (SQUARES[j] & b.pieces[11]) > 0 {
+            } else if (sq & b.pieces[11]) > 0 {
                 out.push('k');
             } else {
                 out.push('-');
             }
 
-            if (SQUARES[j] & m) > 0 {
+            if (sq & m) > 0 {
                 out.push(')');
             } else {
                 out.push(' ');
@@ -667,3 +832,97 @@ pub fn print_bb(m: u64, b: &Board) {
 
     println!("{}", out);
 }
+
+#[test]
+fn evasions_test() {
+    let legal = |b: &mut Board, moves: Vec<Move>| -> Vec<(u8, u8, u8)> {
+        let mut legal: Vec<_> = moves.into_iter().filter(|m| {
+            b.make_no_hashing(m);
+            let legal = in_check_next(b) == 0;
+            b.unmake_no_hashing(m);
+            legal
+        }).map(|m| (m.from, m.to, m.promo_piece)).collect();
+        legal.sort();
+        legal
+    };
+
+    // every position in check a few plies down has to get the same legal moves either way
+    fn walk(b: &mut Board, depth: usize, check: &mut dyn FnMut(&mut Board)) {
+        if in_check_now(b) > 0 {
+            check(b);
+        }
+        if depth == 0 {
+            return;
+        }
+        for m in gen_moves(b) {
+            b.make_no_hashing(&m);
+            if in_check_next(b) == 0 {
+                walk(b, depth - 1, check);
+            }
+            b.unmake_no_hashing(&m);
+        }
+    }
+
+    let mut checked = 0;
+    for fen in [
+        // rook check that can be blocked, captured or stepped away from
+        "4r1k1/8/8/8/8/2N5/3B4/4K3 w - - 0 1",
+        // double check
+        "4r1k1/8/8/8/8/3n4/R7/4K3 w - - 0 1",
+        // pawn check that can be taken en passant
+        "8/8/8/2k5/3Pp3/8/8/4K3 b - d3 0 1",
+        // knight check
+        "4k3/8/3N4/8/8/8/p7/4K3 b - - 0 1",
+        // the pawn that just gave check by double pushing can be taken en passant
+        "8/8/8/3pP3/2K5/8/8/k7 w - d6 0 1",
+        // blocking by promoting
+        "r3k3/1P6/8/8/8/8/8/K7 w - - 0 1",
+        "r3k2r/p1ppqpb1/bn2pnp1/3PN3/1p2P3/2N2Q1p/PPPBBPPP/R3K2R w KQkq - 0 1",
+        "8/2p5/3p4/KP5r/1R3p1k/8/4P1P1/8 w - - 0 1",
+    ] {
+        let mut b = Board::new_from_fen(fen);
+        walk(&mut b, 3, &mut |b: &mut Board| {
+            let (evasions, all) = (gen_evasions(b), gen_moves(b));
+            assert!(evasions.len() <= all.len());
+            assert_eq!(legal(b, evasions), legal(b, all));
+            checked += 1;
+        });
+    }
+    assert!(checked > 1000);
+}
+
+#[test]
+fn gives_check_test() {
+    // every legal move a few plies down has to agree with actually making it
+    fn walk(b: &mut Board, depth: usize, checks: &mut usize) {
+        for m in gen_moves(b) {
+            let gives_check = gives_check(b, &m);
+            b.make_no_hashing(&m);
+            if in_check_next(b) == 0 {
+                assert_eq!(gives_check, in_check_now(b) > 0, "{} in {}", m, b);
+                *checks += gives_check as usize;
+                if depth > 1 {
+                    walk(b, depth - 1, checks);
+                }
+            }
+            b.unmake_no_hashing(&m);
+        }
+    }
+
+    let mut checks = 0;
+    for fen in [
+        "r3k2r/p1ppqpb1/bn2pnp1/3PN3/1p2P3/2N2Q1p/PPPBBPPP/R3K2R w KQkq - 0 1",
+        "8/2p5/3p4/KP5r/1R3p1k/8/4P1P1/8 w - - 0 1",
+        // discovered checks by en passant along the rank
+        "8/8/8/R2pP2k/8/8/8/K7 w - d6 0 1",
+        // castling into check with the rook
+        "5k2/8/8/8/8/8/8/R3K2R w KQ - 0 1",
+        "r3k2r/8/8/8/8/8/8/3K4 b kq - 0 1",
+        // promotions checking straight away or from behind another piece
+        "8/RP1k1P2/8/8/8/8/8/4K3 w - - 0 1",
+    ] {
+        let mut b = Board::new_from_fen(fen);
+        walk(&mut b, 3, &mut checks);
+    }
+    assert!(checks > 1000);
+}
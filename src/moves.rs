@@ -196,9 +196,13 @@ impl Move {
 
         let promo_piece= (promo.unwrap_or(12)) as u8;
 
+        Move::new_from_squares(from, to, promo_piece, b)
+    }
 
+    // works out the rest of the move from the board, used for moves from text and the tt
+    pub fn new_from_squares(from: u8, to: u8, promo_piece: u8, b: &Board) -> Move {
         let piece = movegen::get_piece(from as usize, b) as u8;
-        let xpiece = movegen::get_xpiece(to as usize, b) as u8;
+        let mut xpiece = movegen::get_xpiece(to as usize, b) as u8;
 
         let mut move_type = MoveType::Quiet;
 
@@ -224,8 +228,10 @@ impl Move {
             move_type = MoveType::PromoCapture;
         } else if promo_piece < 12 {
             move_type = MoveType::Promo;
-        } else if xpiece < 2 && to as u8 == b.ep {
+        } else if piece < 2 && to == b.ep {
+            // ep target square is empty so the captured pawn has to be set explicitly
             move_type = MoveType::EpCapture;
+            xpiece = 1 - b.colour as u8;
         } else if xpiece < 12 {
             move_type = MoveType::Capture;
         }
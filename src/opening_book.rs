@@ -4428,7 +4428,7 @@ black_opens: vec![
         } 
         //if blacks turn to move
         let mut opening_move = String::new();
-        if move_num % 2 == 0 {
+        if move_num.is_multiple_of(2) {
             for m in &self.black_opens {
                 if m.starts_with(position) {
                     opening_move = String::from(*m);
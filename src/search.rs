@@ -1,195 +1,749 @@
+use std::sync::atomic::{ AtomicBool, AtomicU64, Ordering };
+use std::thread;
 use std::time::{Duration, Instant};
 
 use crate::movegen::{self, gen_attk, in_check_now};
-use crate::move_ordering::{MoveOrderList, RootOrderList, KillerMoves};
+use crate::move_ordering::{MoveOrderList, RootOrderList, KillerMoves, History};
 use crate::moves::MoveType;
 use crate::{ Board, Move, TTable };
-use crate::eval::{self, STALEMATE, CHECKMATE};
+use crate::eval::{self, STALEMATE, CHECKMATE, MATE_BOUND, TB_WIN, TB_WIN_BOUND};
+use crate::syzygy::{Tablebases, Wdl};
 use crate::transposition_table::{ NodeType, TEntry };
 
 pub const MAX_SEARCH_DEPTH: usize = 50;
+// longest line the pv table can hold
+pub const MAX_PLY: usize = 128;
+// how often (in nodes) the clock and the gui stop flag get checked
+const STOP_CHECK_MASK: u64 = 0x3FF;
+// used when the gui doesnt send any clock info with go
 const TIME_LIM_MS: u64 = 5000;
+// time kept back each move to cover gui/communication lag
+const MOVE_OVERHEAD_MS: u64 = 50;
+// assume sudden death games have this many moves left to spread the clock over
+const DEFAULT_MOVES_TO_GO: u64 = 30;
+// null move pruning is only tried this far from the horizon
+const NULL_MOVE_MIN_DEPTH: usize = 3;
+// late move reductions start at this depth and after this many moves have been searched
+const LMR_MIN_DEPTH: usize = 3;
+const LMR_MIN_MOVES: usize = 3;
+// late move pruning only happens this close to the horizon
+const LMP_MAX_DEPTH: usize = 3;
+// singular extensions need a tt entry at most this much shallower than the current depth
+const SE_MIN_DEPTH: usize = 6;
+const SE_TT_DEPTH_MARGIN: usize = 3;
+// static eval pruning near the leaves, margins are per ply of depth left
+const RFP_MAX_DEPTH: usize = 3;
+const RFP_MARGIN: i32 = 100;
+const FUTILITY_MAX_DEPTH: usize = 3;
+const FUTILITY_MARGIN: i32 = 120;
+const RAZOR_MAX_DEPTH: usize = 2;
+const RAZOR_MARGIN: i32 = 300;
+// pv nodes without a tt move get one from a search this much shallower, other nodes are just searched a ply shallower
+const IID_MIN_DEPTH: usize = 5;
+const IID_REDUCTION: usize = 2;
+const IIR_MIN_DEPTH: usize = 4;
+// aspiration windows start this wide around the last score from this depth, doubling on each fail
+const ASPIRATION_MIN_DEPTH: usize = 4;
+const ASPIRATION_WINDOW: i32 = 25;
+// past this the window is opened up completely
+const ASPIRATION_MAX_WINDOW: i32 = 1000;
+// tablebase results are stored in the tt this much deeper than the node as no search will improve on them
+const TB_TT_DEPTH_BONUS: usize = 6;
+// tablebase wins are reported as this many centipawns less the plies to get there
+const TB_WIN_CP: i32 = 20000;
 
 #[derive(Clone, Copy)]
 pub struct TimeControl {
     pub start_time: Instant,
-    pub additional: u64,
+    // dont start another iteration once past the soft limit
+    pub soft_lim: Duration,
+    // abort the search outright once past the hard limit
+    pub hard_lim: Duration,
+    pub max_depth: usize,
+    pub max_nodes: u64,
+    // keep searching until the gui sends stop
+    pub infinite: bool,
+    // searching on the opponents time, the clock only starts on ponderhit
+    pub ponder: bool,
 }
 
 impl TimeControl {
     pub fn new(start_time: Instant) -> TimeControl {
-        TimeControl { start_time, additional: 0 }
+        TimeControl { 
+            start_time, 
+            soft_lim: Duration::from_millis(TIME_LIM_MS / 2), 
+            hard_lim: Duration::from_millis(TIME_LIM_MS),
+            max_depth: MAX_SEARCH_DEPTH - 1,
+            max_nodes: u64::MAX,
+            infinite: false,
+            ponder: false,
+        }
     }
 
     pub fn new_now() -> TimeControl {
-        TimeControl { start_time: Instant::now(), additional: 0 }
+        TimeControl::new(Instant::now())
+    }
+
+    // allocates the time for this move from the clock of the side to move
+    pub fn new_clock(start_time: Instant, time_left: u64, inc: u64, movestogo: Option<u64>) -> TimeControl {
+        let time_left = time_left.saturating_sub(MOVE_OVERHEAD_MS);
+        let moves_to_go = movestogo.unwrap_or(DEFAULT_MOVES_TO_GO).clamp(1, DEFAULT_MOVES_TO_GO);
+
+        // never plan to spend more than most of whats left on the clock
+        let max_time = time_left * 4 / 5;
+        let hard = (time_left / moves_to_go * 3 + inc).min(max_time);
+        let soft = (time_left / moves_to_go + inc * 3 / 4).min(hard);
+
+        TimeControl { 
+            soft_lim: Duration::from_millis(soft), 
+            hard_lim: Duration::from_millis(hard),
+            ..TimeControl::new(start_time)
+        }
+    }
+
+    // searches for exactly the given time, used by go movetime
+    pub fn new_movetime(start_time: Instant, movetime: u64) -> TimeControl {
+        let movetime = Duration::from_millis(movetime.saturating_sub(MOVE_OVERHEAD_MS));
+
+        TimeControl { 
+            soft_lim: movetime, 
+            hard_lim: movetime,
+            ..TimeControl::new(start_time)
+        }
+    }
+
+    // no time limit, search is only stopped by depth/node limits or the gui
+    pub fn new_unlimited(start_time: Instant) -> TimeControl {
+        TimeControl { 
+            soft_lim: Duration::MAX, 
+            hard_lim: Duration::MAX,
+            ..TimeControl::new(start_time)
+        }
+    }
+
+    #[inline(always)]
+    pub fn soft_stop(&self) -> bool {
+        self.start_time.elapsed() >= self.soft_lim
     }
-    pub fn add_time(&mut self, milli: u64){
-        self.additional += milli;
+
+    #[inline(always)]
+    pub fn hard_stop(&self) -> bool {
+        self.start_time.elapsed() >= self.hard_lim
+    }
+}
+
+// flags shared between the uci thread and all the search threads
+pub struct SearchSignals {
+    pub stop: AtomicBool,
+    pub ponder: AtomicBool,
+    // nodes searched by every thread, each thread adds to it every STOP_CHECK_MASK nodes
+    pub nodes: AtomicU64,
+}
+
+impl SearchSignals {
+    pub fn new() -> SearchSignals {
+        SearchSignals { stop: AtomicBool::new(false), ponder: AtomicBool::new(false), nodes: AtomicU64::new(0) }
     }
 }
 
 pub struct Search<'a> {
     pub board: Board,
-    pub tt: &'a mut TTable,
-    pub tc: TimeControl
+    pub tt: &'a TTable,
+    pub tc: TimeControl,
+    // number of best lines to search and report
+    pub multi_pv: usize,
+    // 0 is the main thread which reports to the gui, lazy smp helpers just fill the shared tt
+    pub thread_id: usize,
+    pub nodes: u64,
+    // nodes already added to signals.nodes
+    flushed_nodes: u64,
+    pub seldepth: usize,
+    // quiet move history, owned by the uci loop between searches
+    pub history: History,
+    tt_hits: u64,
+    tt_misses: u64,
+    // endgame tablebases, none if no SyzygyPath is set
+    pub tb: Option<&'a Tablebases>,
+    tb_hits: u64,
+    pub signals: &'a SearchSignals,
+    stopped: bool,
+    completed_depth: usize,
+    // triangular pv table, pv_table[ply] holds the best line found from that ply
+    pv_table: Vec<Vec<Move>>,
+    // move skipped at each ply while checking if the tt move is singular
+    excluded: [Option<Move>; MAX_PLY],
+    // two quiet moves per ply that caused beta cutoffs, shared by every node at that ply
+    killers: [KillerMoves; MAX_PLY],
+    // move made at each ply, none for a null move
+    played: [Option<Move>; MAX_PLY],
+    // depth of the current iteration, extensions stop past twice this many plies
+    root_depth: usize,
+    // late move reductions indexed by depth and move number
+    lmr_table: [[u8; 64]; 64],
+    // principal variation of the last full iteration
+    pub pv: Vec<Move>,
 }
 
 impl <'a> Search<'a> {
-    pub fn new(board: Board, tt: &'a mut TTable, tc: TimeControl) -> Search<'a> {
-        Search { board, tt, tc }
+    pub fn new(board: Board, tt: &'a TTable, tc: TimeControl, signals: &'a SearchSignals) -> Search<'a> {
+        Search::new_with_history(board, tt, tc, signals, History::new())
     }
 
-    pub fn iterative_deepening_search(&mut self) -> Option<Move> {
-        self.tt.hit_count = 0;
-        self.tt.miss_count = 0;
+    // carries on with the history of an earlier search rather than allocating a fresh one
+    pub fn new_with_history(board: Board, tt: &'a TTable, tc: TimeControl, signals: &'a SearchSignals, history: History) -> Search<'a> {
+        Search { 
+            board, 
+            tt, 
+            tc, 
+            multi_pv: 1,
+            thread_id: 0,
+            nodes: 0, 
+            flushed_nodes: 0,
+            seldepth: 0,
+            history,
+            tt_hits: 0,
+            tt_misses: 0,
+            tb: None,
+            tb_hits: 0,
+            signals, 
+            stopped: false, 
+            completed_depth: 0,
+            pv_table: (0..MAX_PLY).map(|_| Vec::with_capacity(MAX_PLY)).collect(),
+            excluded: [None; MAX_PLY],
+            killers: [KillerMoves::new(); MAX_PLY],
+            played: [None; MAX_PLY],
+            root_depth: 0,
+            lmr_table: init_lmr_table(),
+            pv: Vec::new(),
+        }
+    }
+
+    // returns the principal variation, the first move of which is the best move
+    pub fn iterative_deepening_search(&mut self) -> Vec<Move> {
+        if self.is_main() {
+            self.tt.new_search();
+        }
+        self.history.age();
 
         let mut root = RootOrderList::new(&self.board);
-        let mut km = KillerMoves::new();
+        self.filter_root_tb(&mut root);
+        let mut lines: Vec<(i32, Vec<Move>)> = Vec::new();
+
+        // helpers start on alternating depths so the threads dont all search the same tree
+        let first_depth = 1 + self.thread_id % 2;
+        for depth in first_depth..=self.tc.max_depth.min(MAX_SEARCH_DEPTH - 1) {
+            // always complete the first iteration so there is a move to play
+            if depth > 1 && (self.out_of_time(false) || self.nodes >= self.tc.max_nodes || self.signals.stop.load(Ordering::Relaxed)) {
+                break;
+            }
 
-        for depth in 1..MAX_SEARCH_DEPTH {
-            let mut complete = false;
-            if self.tc.start_time.elapsed() <= Duration::from_millis(TIME_LIM_MS / 2){
-                self.root_search(&mut root, &mut km, depth);
-                complete = true;
+            self.seldepth = 0;
+            self.root_depth = depth;
+            // killers from the last iteration are still a good guess but new ones should take over
+            for km in self.killers.iter_mut() {
+                km.age();
+            }
+            let prev_score = lines.first().map(|(score, _)| *score);
+            lines = Vec::with_capacity(self.multi_pv);
+            
+            // each extra pv is searched without the moves from the lines above it
+            for i in 0..self.multi_pv {
+                let excluded: Vec<Move> = lines.iter().map(|(_, pv)| pv[0]).collect();
+                let score = if i == 0 {
+                    self.aspiration_search(&mut root, depth, prev_score)
+                } else {
+                    self.root_search(&mut root, depth, &excluded, i32::MIN + 1, i32::MAX)
+                };
+
+                if self.stop_search() || self.pv_table[0].is_empty() {
+                    break;
+                }
+                lines.push((score, self.pv_table[0].clone()));
             }
             
             // cut off any potentially unstable moves
-            if self.tc.start_time.elapsed() >= Duration::from_millis(TIME_LIM_MS + self.tc.additional) {
-                println!("info string cut early");
+            if self.stop_search() {
+                if self.is_main() {
+                    println!("info string cut early");
+                }
                 break;
             }
             
-            if complete {
-                println!("info string completed iteration");
-                println!("info string {}ms left", (TIME_LIM_MS + self.tc.additional) as u128 - self.tc.start_time.elapsed().as_millis());
-                self.tc.add_time(500*depth as u64);
-            } 
-                
+            root.done_iteration();
+            self.completed_depth = depth;
+            if let Some((_, pv)) = lines.first() {
+                self.pv = pv.clone();
+            }
+            if self.is_main() {
+                self.print_info(depth, &lines);
+            }
         }
 
-        println!("info string hitcount={}, miss_count={}", self.tt.hit_count, self.tt.miss_count);
-        root.get_bestmove()
+        if !self.is_main() {
+            return self.pv.clone();
+        }
+
+        // in infinite and ponder mode the gui expects to hear nothing until it sends stop/ponderhit
+        while (self.tc.infinite || self.is_pondering()) && !self.signals.stop.load(Ordering::Relaxed) {
+            thread::sleep(Duration::from_millis(1));
+        }
+
+        println!("info string hitcount={}, miss_count={}", self.tt_hits, self.tt_misses);
+        self.pv.clone()
     }
 
+    // in a tablebase position only search the moves that keep the best result, the search then picks between them
+    fn filter_root_tb(&mut self, root: &mut RootOrderList) {
+        let Some(tb) = self.tb.filter(|tb| tb.can_probe(&self.board)) else {
+            return;
+        };
 
-    pub fn root_search( &mut self, root_moves: &mut RootOrderList, km: &mut KillerMoves, depth: usize) -> i32 {
+        let moves: Vec<Move> = root.now.iter().map(|(m, _)| *m).collect();
+        if let Some(ranks) = tb.rank_root_moves(&mut self.board, self.tt, &moves) {
+            root.retain_best(&ranks);
+            self.tb_hits += 1;
+        }
+    }
+
+    fn print_info(&self, depth: usize, lines: &[(i32, Vec<Move>)]) {
+        let time = self.tc.start_time.elapsed().as_millis() as u64;
+        let nodes = self.total_nodes();
+        let nps = nodes * 1000 / time.max(1);
+
+        for (i, (score, pv)) in lines.iter().enumerate() {
+            let pv: Vec<String> = pv.iter().map(|m| m.as_uci_string()).collect();
+
+            println!(
+                "info depth {} seldepth {} multipv {} score {} nodes {} nps {} time {} hashfull {} tbhits {} pv {}",
+                depth,
+                self.seldepth.max(depth),
+                i + 1,
+                uci_score(*score),
+                nodes,
+                nps,
+                time,
+                self.tt.hashfull(),
+                self.tb_hits,
+                pv.join(" ")
+            );
+        }
+    }
+
+    // searches a narrow window around the score from the last iteration, widening it until the score falls inside
+    fn aspiration_search(&mut self, root_moves: &mut RootOrderList, depth: usize, prev_score: Option<i32>) -> i32 {
+        let mut delta = ASPIRATION_WINDOW;
+        let (mut alpha, mut beta) = match prev_score {
+            Some(score) if depth >= ASPIRATION_MIN_DEPTH && score.abs() < MATE_BOUND => (score - delta, score + delta),
+            _ => (i32::MIN + 1, i32::MAX),
+        };
+
+        loop {
+            let score = self.root_search(root_moves, depth, &[], alpha, beta);
+            // no legal moves, widening wont change anything
+            if self.stop_search() || self.pv_table[0].is_empty() {
+                return score;
+            }
+
+            if score <= alpha {
+                // pull beta in as well, the true score is probably lower than the last one
+                beta = ((alpha as i64 + beta as i64) / 2) as i32;
+                alpha = alpha.saturating_sub(delta).max(i32::MIN + 1);
+            } else if score >= beta {
+                beta = beta.saturating_add(delta);
+            } else {
+                return score;
+            }
+
+            delta *= 2;
+            if delta > ASPIRATION_MAX_WINDOW {
+                alpha = i32::MIN + 1;
+                beta = i32::MAX;
+            }
+        }
+    }
+
+    // searches every root move not in excluded, leaving the best line in pv_table[0].
+    // returns as soon as a move reaches beta so the window can be widened
+    pub fn root_search( &mut self, root_moves: &mut RootOrderList, depth: usize, excluded: &[Move], alpha: i32, beta: i32) -> i32 {
         let mut best_move = None;
         let mut best_score = i32::MIN+1;
         let player = if self.board.colour == 0 { 1 } else { -1 };
 
         root_moves.sort();
+        self.pv_table[0].clear();
+
+        for index in 0..root_moves.now.len() {
+            let m = root_moves.now[index].0;
+            if excluded.contains(&m) {
+                continue;
+            }
 
-        for (index, (m, curr_score)) in root_moves.now.iter().enumerate() {
-            if self.tc.start_time.elapsed() >= Duration::from_millis(TIME_LIM_MS + self.tc.additional) {
-                println!("info string incomplete search");
+            if self.stop_search() {
                 return best_score;
             }
 
-            self.board.make(m, self.tt);
+            self.board.make(&m, self.tt);
 
             if movegen::in_check_next(&self.board) > 0 {
-                self.board.unmake(m, self.tt);
+                self.board.unmake(&m, self.tt);
                 continue;
             }
 
-            let score = -self.pvs(i32::MIN + 1, -best_score, depth-1, MAX_SEARCH_DEPTH as i32, -player, km);
+            self.played[0] = Some(m);
+            let score = -self.pvs(-beta, -alpha.max(best_score), depth-1, 1, -player);
+
+            self.board.unmake(&m, self.tt);
+
+            if self.stop_search() {
+                return best_score;
+            }
 
-            root_moves.next[index] = (*m,score);
+            root_moves.next[index] = (m, score);
 
             if score > best_score {
                 best_move = Some(m);
                 best_score = score;
-                
-                println!(
-                    "info cp {}, depth {} currmove {}",
-                    best_score,
-                    depth,
-                    m.as_uci_string()
-                );
+                self.update_pv(0, m);
             } 
 
-            self.board.unmake(m, self.tt);
+            if score >= beta {
+                // search this move first once the window is widened
+                root_moves.now[index].1 = i32::MAX;
+                break;
+            }
         }
         
-        root_moves.done_iteration();
         best_score
     }
 
-    pub fn pvs( &mut self, mut alpha: i32, beta: i32, mut depth: usize, mate_dist: i32, player: i32, km: &mut KillerMoves ) -> i32 {
+    // polled throughout the search, the clock and stop flag are only looked at every so often as they are slow to check
+    pub fn stop_search(&mut self) -> bool {
+        let check = self.nodes & STOP_CHECK_MASK == 0;
+        if check && self.nodes != self.flushed_nodes {
+            self.signals.nodes.fetch_add(self.nodes - self.flushed_nodes, Ordering::Relaxed);
+            self.flushed_nodes = self.nodes;
+        }
+
+        // the main thread always finishes the first iteration so there is a move to play
+        if self.completed_depth == 0 && self.is_main() {
+            return false;
+        }
+
+        if !self.stopped {
+            self.stopped = self.nodes >= self.tc.max_nodes 
+                || (check && (self.signals.stop.load(Ordering::Relaxed) || self.total_nodes() >= self.tc.max_nodes || self.out_of_time(true)));
+        }
+
+        self.stopped
+    }
 
-        if let Some(hash_score) = self.tt.get(self.board.hash, depth as u8, mate_dist, alpha, beta) {
+    #[inline(always)]
+    fn is_main(&self) -> bool {
+        self.thread_id == 0
+    }
+
+    // nodes searched by all threads, only counting this threads nodes exactly
+    fn total_nodes(&self) -> u64 {
+        self.signals.nodes.load(Ordering::Relaxed) + self.nodes - self.flushed_nodes
+    }
+
+    pub fn probe_tt(&mut self, depth: usize, ply: usize, alpha: i32, beta: i32) -> Option<i32> {
+        let score = self.tt.get(self.board.hash, depth as u8, ply, alpha, beta);
+        if score.is_some() {
+            self.tt_hits += 1;
+        } else {
+            self.tt_misses += 1;
+        }
+
+        score
+    }
+
+    fn is_pondering(&mut self) -> bool {
+        if self.tc.ponder && !self.signals.ponder.load(Ordering::Relaxed) {
+            // ponderhit, the opponent played the expected move so our clock starts now
+            self.tc.ponder = false;
+            self.tc.start_time = Instant::now();
+        }
+
+        self.tc.ponder
+    }
+
+    fn out_of_time(&mut self, hard: bool) -> bool {
+        if self.is_pondering() {
+            false
+        } else if hard {
+            self.tc.hard_stop()
+        } else {
+            self.tc.soft_stop()
+        }
+    }
+
+    // moves made 1 and 2 plies before this one
+    fn prev_moves(&self, ply: usize) -> [Option<Move>; 2] {
+        [
+            ply.checked_sub(1).and_then(|p| self.played[p]),
+            ply.checked_sub(2).and_then(|p| self.played[p]),
+        ]
+    }
+
+    // adds m to the front of the line from the next ply to make the line for this ply
+    fn update_pv(&mut self, ply: usize, m: Move) {
+        if ply + 1 >= MAX_PLY {
+            return;
+        }
+
+        let child_pv = std::mem::take(&mut self.pv_table[ply + 1]);
+        self.pv_table[ply].clear();
+        self.pv_table[ply].push(m);
+        self.pv_table[ply].extend_from_slice(&child_pv);
+        self.pv_table[ply + 1] = child_pv;
+    }
+
+    pub fn pvs( &mut self, mut alpha: i32, beta: i32, mut depth: usize, ply: usize, player: i32 ) -> i32 {
+        if ply >= MAX_PLY - 1 {
+            return eval::evaluate(&mut self.board, player);
+        }
+        self.seldepth = self.seldepth.max(ply);
+        self.pv_table[ply].clear();
+
+        // checked before the tt as the tt score doesnt know how the position was reached
+        if self.board.is_draw(ply) {
+            return STALEMATE;
+        }
+
+        // exact hits would cut the pv short, so only take tt cutoffs in null window nodes.
+        // the tt score includes the excluded move, so it cant be used while checking for singular moves
+        let pv_node = beta as i64 - alpha as i64 > 1;
+        let excluded = self.excluded[ply];
+        if let Some(hash_score) = self.probe_tt(depth, ply, alpha, beta).filter(|_| !pv_node && excluded.is_none()) {
             return hash_score;
-        } else if self.board.is_bad_pos() {
-            // prefer mates further down the line to potentially find more favourable positions later on
-            return STALEMATE; 
+        }
+        // read once here and handed to everything below that wants the tt move
+        let mut tt_entry = self.tt.get_entry(self.board.hash, ply);
+        let mut tt_move = tt_entry.and_then(|e| e.get_bestmove(&self.board));
+
+        // check extension, dont drop into quiesce while in check
+        let in_check = movegen::in_check_now(&self.board) > 0;
+        let can_extend = ply < 2 * self.root_depth;
+        if in_check && can_extend {
+            depth += 1;
         }
 
         if depth == 0 {
-            let eval = eval::quiesce(self, alpha, beta, mate_dist-1, player);
-            if eval < CHECKMATE{
-                self.tt.insert(TEntry::new(self.board.hash, None, 0, eval, NodeType::Pv));
-            } else {
-                self.tt.insert(TEntry::new(self.board.hash, None, 0, eval, NodeType::Beta))
+            return eval::quiesce(self, alpha, beta, ply, 0, player);
+        }
+
+        self.nodes += 1;
+
+        // tablebase probe, the material only changes after a capture or pawn move so thats the only time to look
+        if let Some(tb) = self.tb.filter(|tb| excluded.is_none() && self.board.halfmove == 0 && tb.can_probe(&self.board)) {
+            if let Some(wdl) = tb.probe_wdl(&mut self.board) {
+                self.tb_hits += 1;
+
+                let (score, node_type) = match wdl {
+                    Wdl::Win => (TB_WIN - ply as i32, NodeType::Beta),
+                    Wdl::Loss => (-TB_WIN + ply as i32, NodeType::Alpha),
+                    // wins and losses the fifty move rule turns into draws are only just better than a draw
+                    wdl => (wdl as i32, NodeType::Pv),
+                };
+
+                if node_type == NodeType::Pv || (node_type == NodeType::Beta && score >= beta) || (node_type == NodeType::Alpha && score <= alpha) {
+                    let tt_depth = (depth + TB_TT_DEPTH_BONUS).min(MAX_PLY - 1);
+                    self.tt.insert(TEntry::new(self.board.hash, None, tt_depth as u8, score, node_type, ply));
+                    return score.clamp(alpha, beta);
+                }
             }
-            return eval;
         }
 
         let mut best_move = None;
         let mut no_moves = true;
-        let mut checkmate = false;
         let mut node_type = NodeType::Alpha;
 
-        let mut sub_km = KillerMoves::new();
+        // static eval isnt meaningful in check, and none of the pruning below is done in pv nodes or singular searches
+        let can_prune = !pv_node && !in_check && excluded.is_none();
+        let static_eval = if can_prune { eval::evaluate(&mut self.board, player) } else { 0 };
+
+        // reverse futility pruning, too far above beta for the last few plies to bring it back down
+        if can_prune && depth <= RFP_MAX_DEPTH && beta.abs() < MATE_BOUND && static_eval - RFP_MARGIN * depth as i32 >= beta {
+            return beta;
+        }
+
+        // razoring, so far below alpha that only captures could help, so just check those
+        if can_prune && depth <= RAZOR_MAX_DEPTH && alpha.abs() < MATE_BOUND && static_eval + RAZOR_MARGIN * (depth as i32) < alpha {
+            let score = eval::quiesce(self, alpha, beta, ply, 0, player);
+            if score <= alpha {
+                return alpha;
+            }
+        }
+
+        // null move pruning, if passing still beats beta then a real move will too.
+        // not done twice in a row, in check, or with only pawns left where zugzwang is common
+        if can_prune
+            && depth >= NULL_MOVE_MIN_DEPTH 
+            && ply + 1 < MAX_PLY
+            && !self.board.last_move_null() 
+            && self.board.has_non_pawn_material() 
+            && static_eval >= beta
+        {
+            // reduce more the deeper the search and the further ahead we are
+            let r = 3 + depth / 6 + ((static_eval - beta) / 200).min(3) as usize;
+
+            let ep = self.board.make_null(self.tt);
+            self.played[ply] = None;
+            let score = -self.pvs(-beta, -beta + 1, depth.saturating_sub(r + 1), ply + 1, -player);
+            self.board.unmake_null(ep, self.tt);
+
+            if self.stop_search() {
+                return alpha;
+            }
+            if score >= beta {
+                return beta;
+            }
+        }
+
+        // internal iterative deepening, without a tt move the ordering is mostly guesswork
+        if excluded.is_none() && tt_move.is_none() {
+            if pv_node && depth >= IID_MIN_DEPTH {
+                // the shallow search leaves its best move in the tt for the full search to try first
+                self.pvs(alpha, beta, depth - IID_REDUCTION, ply, player);
+                self.pv_table[ply].clear();
+
+                if self.stop_search() {
+                    return alpha;
+                }
+                tt_entry = self.tt.get_entry(self.board.hash, ply);
+                tt_move = tt_entry.and_then(|e| e.get_bestmove(&self.board));
+            } else if !pv_node && depth >= IIR_MIN_DEPTH {
+                // a cut node this deep with no tt move is probably less important than it looks
+                depth -= 1;
+            }
+        }
+
+        // singular extension, if every other move fails well below the tt score then the tt move is forced and worth a ply more
+        let mut singular_move = None;
+        if depth >= SE_MIN_DEPTH && excluded.is_none() && can_extend {
+            if let (Some(entry), Some(tt_move)) = (tt_entry, tt_move) {
+                if entry.depth as usize + SE_TT_DEPTH_MARGIN >= depth 
+                    && entry.node_type != NodeType::Alpha 
+                    && entry.score.abs() < MATE_BOUND 
+                {
+                    let singular_beta = entry.score - 2 * depth as i32;
+
+                    self.excluded[ply] = Some(tt_move);
+                    let score = self.pvs(singular_beta - 1, singular_beta, (depth - 1) / 2, ply, player);
+                    self.excluded[ply] = None;
+                    self.pv_table[ply].clear();
+
+                    if self.stop_search() {
+                        return alpha;
+                    }
+                    if score < singular_beta {
+                        singular_move = Some(tt_move);
+                    } else if singular_beta >= beta {
+                        // multi cut, more than one move beats beta
+                        return beta;
+                    }
+                }
+            }
+        }
 
         let og_hash = self.board.hash; 
         // staged move ordering - generates the pv and captures first and then afterwards the quiet moves 
-        let captures = |b:&mut Board, _km: &KillerMoves, tt: &TTable|  MoveOrderList::new_pv_attacks(b, &movegen::gen_attk(b), tt);
-        let quiet = |b:&mut Board, km: &KillerMoves, tt: &TTable| MoveOrderList::new_quiet(b, &movegen::gen_quiet(b), km, tt);
+        let captures = |b:&mut Board, _km: &KillerMoves, tt_move: Option<Move>, _hist: &History, _prev: &[Option<Move>; 2]|  MoveOrderList::new_pv_attacks(b, &movegen::gen_attk(b), tt_move);
+        let quiet = |b:&mut Board, km: &KillerMoves, tt_move: Option<Move>, hist: &History, prev: &[Option<Move>; 2]| MoveOrderList::new_quiet(&movegen::gen_quiet(b), km, tt_move, hist, prev);
+        let prev = self.prev_moves(ply);
+        let mut quiets_tried = Vec::new();
 
         let mut is_pv = true;
+        let mut moves_searched = 0;
+        // quiet moves cant raise the eval enough to reach alpha
+        let futile = can_prune && depth <= FUTILITY_MAX_DEPTH && alpha.abs() < MATE_BOUND 
+            && static_eval + FUTILITY_MARGIN * depth as i32 <= alpha;
         
         for moveset in [captures, quiet]{
-            let moves = moveset(&mut self.board, km, self.tt);
+            let moves = moveset(&mut self.board, &self.killers[ply], tt_move, &self.history, &prev);
             for m in moves {
-                if self.tc.start_time.elapsed() >= Duration::from_millis(TIME_LIM_MS + self.tc.additional) {
-                    println!("info string incomplete search");
-                    break;
+                // result of an unfinished search isnt worth keeping in the tt
+                if self.stop_search() {
+                    return alpha;
+                }
+
+                if excluded == Some(m) {
+                    continue;
+                }
+
+                let is_quiet = m.xpiece == 12 && m.promo_piece == 12 && self.killers[ply].contains(m) == 0;
+
+                // late move pruning, near the horizon quiet moves this far down the list are very unlikely to be best
+                if !pv_node && !in_check && is_quiet && depth <= LMP_MAX_DEPTH && moves_searched >= 3 + depth * depth {
+                    continue;
                 }
 
                 self.board.make(&m, self.tt);
 
                 if movegen::in_check_next(&self.board) > 0 {
                     self.board.unmake(&m, self.tt);
-                    checkmate = true;
                     continue;
                 } else {
                     no_moves = false;
                 }
+                moves_searched += 1;
+                let gives_check = movegen::in_check_now(&self.board) > 0;
+
+                // futility pruning, always search one move so the node still has a score
+                if futile && is_quiet && moves_searched > 1 && !gives_check {
+                    self.board.unmake(&m, self.tt);
+                    continue;
+                }
+
+                if m.xpiece == 12 && m.promo_piece == 12 {
+                    quiets_tried.push(m);
+                }
+                self.played[ply] = Some(m);
 
                 let mut score: i32;
+                let new_depth = if singular_move == Some(m) { depth } else { depth - 1 };
 
                 if is_pv {
-                    score = -self.pvs(-beta,-alpha,depth - 1, mate_dist - 1, -player, &mut sub_km);
+                    score = -self.pvs(-beta,-alpha,new_depth, ply + 1, -player);
                     is_pv = false;
                 } else {
-                    score = -self.pvs(-alpha-1,-alpha,depth - 1, mate_dist - 1, -player, &mut sub_km);
+                    // late move reductions, later quiet moves get a shallower null window search first
+                    let mut reduction = 0;
+                    if is_quiet && !in_check && depth >= LMR_MIN_DEPTH && moves_searched > LMR_MIN_MOVES && !gives_check {
+                        reduction = self.lmr_table[depth.min(63)][moves_searched.min(63)] as usize;
+                        if pv_node {
+                            reduction = reduction.saturating_sub(1);
+                        }
+                        reduction = reduction.min(depth - 2);
+                    }
+
+                    score = -self.pvs(-alpha-1,-alpha,new_depth - reduction, ply + 1, -player);
+                    if score > alpha && reduction > 0 {
+                        score = -self.pvs(-alpha-1,-alpha,new_depth, ply + 1, -player);
+                    }
                     if score > alpha {
-                        score = -self.pvs(-beta,-alpha,depth - 1, mate_dist - 1, -player, &mut sub_km);
+                        score = -self.pvs(-beta,-alpha,new_depth, ply + 1, -player);
                     }
                 }
 
                 self.board.unmake(&m, self.tt);
 
+                if self.stop_search() {
+                    return alpha;
+                }
+
                 if score >= beta {
                     match m.move_type {
                        MoveType::Capture | MoveType::EpCapture | MoveType::PromoCapture => {},
-                       _  => km.push(m)
+                       _  => self.killers[ply].push(m)
                     }
 
-                    self.tt.insert(TEntry::new(self.board.hash, None, depth as u8, beta, NodeType::Beta));
-                    if m.xpiece == 12 {
-                        self.tt.inc_hh(m.piece, m.to, depth as i32);
+                    if excluded.is_none() {
+                        self.tt.insert(TEntry::new(self.board.hash, Some(m), depth as u8, beta, NodeType::Beta, ply));
+                    }
+                    if m.xpiece == 12 && m.promo_piece == 12 {
+                        self.history.update(&m, &quiets_tried, &prev, depth);
                     }
                     return beta;
                 }
@@ -198,22 +752,31 @@ impl <'a> Search<'a> {
                     node_type = NodeType::Pv;
                     best_move = Some(m);
                     alpha = score;
+                    self.update_pv(ply, m);
                 } 
             }
         }
+        // a singular search only needs the score, it shouldnt replace the tt entry for the full node
+        // and having no other legal move means the excluded one is singular rather than mate
+        if excluded.is_some() {
+            return alpha;
+        }
+
         // if checkmate/stalemate
         if no_moves {
-            if checkmate {
+            if in_check {
+                // mates closer to the root score higher for the side doing the mating
+                let score = CHECKMATE + ply as i32;
                 self.tt.insert(TEntry::new(
                     self.board.hash,
                     None,
                     depth as u8,
-                    eval::CHECKMATE * mate_dist,
+                    score,
                     NodeType::Pv,
+                    ply,
                 ));
                 
-
-                eval::CHECKMATE * mate_dist
+                score
             
             } else {
                 self.tt.insert(TEntry::new(
@@ -221,7 +784,8 @@ impl <'a> Search<'a> {
                     None, 
                     depth as u8, 
                     0, 
-                    NodeType::Pv
+                    NodeType::Pv,
+                    ply,
                 ));
             
                 STALEMATE
@@ -233,9 +797,76 @@ impl <'a> Search<'a> {
                 depth as u8,
                 alpha,
                 node_type,
+                ply,
             ));
 
             alpha
         }
     }
-}
\ No newline at end of file
+}
+
+// reduction = 0.75 + ln(depth) * ln(moves) / 2.25
+fn init_lmr_table() -> [[u8; 64]; 64] {
+    let mut lmr_table = [[0; 64]; 64];
+
+    for (depth, row) in lmr_table.iter_mut().enumerate().skip(1) {
+        for (moves, r) in row.iter_mut().enumerate().skip(1) {
+            *r = (0.75 + (depth as f64).ln() * (moves as f64).ln() / 2.25) as u8;
+        }
+    }
+
+    lmr_table
+}
+
+// formats a root score as either centipawns or moves till mate, tablebase wins are shown as a big centipawn score
+fn uci_score(score: i32) -> String {
+    if score >= MATE_BOUND {
+        // mated node is always on an odd ply when we are doing the mating
+        let ply = -CHECKMATE - score;
+        format!("mate {}", (ply + 1) / 2)
+    } else if score <= -MATE_BOUND {
+        let ply = score - CHECKMATE;
+        format!("mate -{}", ply / 2)
+    } else if score.abs() >= TB_WIN_BOUND {
+        format!("cp {}", score.signum() * (TB_WIN_CP - (TB_WIN - score.abs())))
+    } else {
+        format!("cp {}", score)
+    }
+}
+
+#[test]
+fn pv_test() {
+    let tt = TTable::new();
+    let signals = SearchSignals::new();
+    let mut board = Board::new_from_fen("r1bqkbnr/pppp1ppp/2n5/4p3/4P3/5N2/PPPP1PPP/RNBQKB1R w KQkq - 2 3");
+    board.hash = board.get_hash(&tt);
+
+    let mut tc = TimeControl::new_unlimited(Instant::now());
+    tc.max_depth = 5;
+    let pv = Search::new(board.clone(), &tt, tc, &signals).iterative_deepening_search();
+    assert!(pv.len() > 1);
+
+    // every move in the line should be playable from the root
+    for m in &pv {
+        assert!(movegen::gen_moves(&board).contains(m));
+        board.make(m, &tt);
+        assert_eq!(movegen::in_check_next(&board), 0);
+    }
+}
+
+#[test]
+fn mate_score_test() {
+    let tt = TTable::new();
+    let signals = SearchSignals::new();
+    // kb6 kb8 rh8#
+    let mut board = Board::new_from_fen("k7/8/2K5/8/8/8/8/7R w - - 0 1");
+    board.hash = board.get_hash(&tt);
+
+    let mut search = Search::new(board.clone(), &tt, TimeControl::new_unlimited(Instant::now()), &signals);
+    let mut root = RootOrderList::new(&board);
+    let score = search.root_search(&mut root, 5, &[], i32::MIN + 1, i32::MAX);
+
+    assert_eq!(uci_score(score), "mate 2");
+    assert_eq!(uci_score(CHECKMATE + 4), "mate -2");
+    assert_eq!(uci_score(150), "cp 150");
+}
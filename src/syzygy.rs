@@ -0,0 +1,1241 @@
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::ops::Neg;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+use memmap2::Mmap;
+
+use crate::{ Board, Move, TTable };
+use crate::movegen::{self, bitscn_fw};
+
+/*
+--- syzygy tablebases ---
+reads the .rtbw (win/draw/loss) and .rtbz (distance to zeroing) files, following the layout and
+indexing of the original probing code. the files are mapped and their headers read when the path is set,
+so the search only ever touches memory.
+*/
+
+// most pieces on the board in any syzygy table
+const TB_PIECES: usize = 7;
+const WDL_MAGIC: [u8; 4] = [0x71, 0xE8, 0x23, 0x5D];
+const DTZ_MAGIC: [u8; 4] = [0xD7, 0x66, 0x0C, 0xA5];
+
+// file header flags
+const FILE_SPLIT: u8 = 1;
+const FILE_HAS_PAWNS: u8 = 2;
+
+// flags of each compressed table
+const FLAG_STM: u8 = 1;
+const FLAG_MAPPED: u8 = 2;
+const FLAG_WIN_PLIES: u8 = 4;
+const FLAG_LOSS_PLIES: u8 = 8;
+const FLAG_WIDE: u8 = 16;
+const FLAG_SINGLE_VALUE: u8 = 128;
+
+// our piece indexes to the codes used in the files, white pnbrqk is 1-6 and black is 9-14
+const TB_PIECE_CODE: [u8; 12] = [1, 9, 2, 10, 4, 12, 3, 11, 5, 13, 6, 14];
+// root move ranks, a win the fifty move rule cant stop ranks above MAX_DTZ / 2
+const MAX_DTZ: i32 = 1 << 18;
+
+// cursed wins and blessed losses are won or lost but drawn by the fifty move rule
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub enum Wdl {
+    Loss = -2,
+    BlessedLoss = -1,
+    Draw = 0,
+    CursedWin = 1,
+    Win = 2,
+}
+
+impl Wdl {
+    fn from_value(value: i32) -> Wdl {
+        match value {
+            i32::MIN..=-2 => Wdl::Loss,
+            -1 => Wdl::BlessedLoss,
+            0 => Wdl::Draw,
+            1 => Wdl::CursedWin,
+            _ => Wdl::Win,
+        }
+    }
+}
+
+impl Neg for Wdl {
+    type Output = Wdl;
+
+    fn neg(self) -> Wdl {
+        Wdl::from_value(-(self as i32))
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+enum ProbeError {
+    // table isnt in the path or couldnt be read
+    Missing,
+    // dtz tables only store one side to move, the other side has to be found with a search
+    ChangeStm,
+}
+
+pub struct Tablebases {
+    tables: Vec<TbTable>,
+    // material key of either colour arrangement to its table
+    keys: HashMap<u64, usize>,
+    max_pieces: usize,
+}
+
+impl Tablebases {
+    pub fn new() -> Tablebases {
+        Tablebases { tables: Vec::new(), keys: HashMap::new(), max_pieces: 0 }
+    }
+
+    // paths are separated the same way as PATH, ie ':' on unix and ';' on windows
+    pub fn new_from_path(paths: &str) -> Tablebases {
+        let mut tb = Tablebases::new();
+        let dirs: Vec<PathBuf> = env::split_paths(paths).collect();
+
+        for dir in &dirs {
+            let Ok(entries) = fs::read_dir(dir) else {
+                continue;
+            };
+
+            for path in entries.flatten().map(|e| e.path()) {
+                if path.extension().is_none_or(|ext| ext != "rtbw") {
+                    continue;
+                }
+                let Some(name) = path.file_stem().and_then(|s| s.to_str()) else {
+                    continue;
+                };
+                let Some(mut table) = TbTable::new(name) else {
+                    continue;
+                };
+                // the first directory with a table wins
+                if tb.keys.contains_key(&table.key) {
+                    continue;
+                }
+
+                table.wdl = TbFile::new(&table, &path, false);
+                if table.wdl.is_none() {
+                    continue;
+                }
+                // the dtz table can be in any of the directories
+                let dtz_name = format!("{}.rtbz", name);
+                table.dtz = dirs.iter().map(|dir| dir.join(&dtz_name)).find(|path| path.is_file())
+                    .and_then(|path| TbFile::new(&table, &path, true));
+
+                tb.keys.insert(table.key, tb.tables.len());
+                tb.keys.insert(table.key2, tb.tables.len());
+                tb.max_pieces = tb.max_pieces.max(table.piece_count);
+                tb.tables.push(table);
+            }
+        }
+
+        tb
+    }
+
+    pub fn len(&self) -> usize {
+        self.tables.len()
+    }
+
+    pub fn max_pieces(&self) -> usize {
+        self.max_pieces
+    }
+
+    // tables dont include castling rights
+    pub fn can_probe(&self, b: &Board) -> bool {
+        b.castle_state == 0 && (b.util[2].count_ones() as usize) <= self.max_pieces
+    }
+
+    // win/draw/loss for the side to move, with the fifty move rule counted from the last capture or pawn move
+    pub fn probe_wdl(&self, b: &mut Board) -> Option<Wdl> {
+        self.search(b, false).ok().map(|(wdl, _)| wdl)
+    }
+
+    // plies until the next capture or pawn move with best play, positive if winning.
+    // can be off by one as the tables only store some positions exactly
+    pub fn probe_dtz(&self, b: &mut Board) -> Option<i32> {
+        self.dtz(b).ok()
+    }
+
+    // ranks each root move, the highest ranked moves keep the best result the fastest.
+    // illegal moves get i32::MIN, and none if a table is missing
+    pub fn rank_root_moves(&self, b: &mut Board, tt: &TTable, moves: &[Move]) -> Option<Vec<i32>> {
+        self.rank_root_moves_dtz(b, tt, moves).or_else(|| self.rank_root_moves_wdl(b, tt, moves))
+    }
+
+    fn rank_root_moves_dtz(&self, b: &mut Board, tt: &TTable, moves: &[Move]) -> Option<Vec<i32>> {
+        let cnt50 = b.halfmove as i32;
+        // once the root has been seen before only the fastest win is safe from repeating forever
+        let rep = b.is_repetition(usize::MAX);
+        let mut ranks = Vec::with_capacity(moves.len());
+
+        for m in moves {
+            b.make(m, tt);
+            if movegen::in_check_next(b) > 0 {
+                b.unmake(m, tt);
+                ranks.push(i32::MIN);
+                continue;
+            }
+
+            let dtz = if b.halfmove == 0 {
+                self.probe_wdl(b).map(|wdl| dtz_before_zeroing(-wdl))
+            } else if b.is_draw(1) {
+                Some(0)
+            } else {
+                self.probe_dtz(b).map(|dtz| -dtz + (-dtz).signum())
+            };
+            // mate in one
+            let dtz = dtz.map(|dtz| if dtz == 2 && movegen::in_check_now(b) > 0 && legal_moves(b).is_empty() { 1 } else { dtz });
+            b.unmake(m, tt);
+            let dtz = dtz?;
+
+            let rank = if dtz > 0 {
+                if dtz + cnt50 <= 99 {
+                    if rep { MAX_DTZ - dtz } else { MAX_DTZ }
+                } else {
+                    MAX_DTZ / 2 - (dtz + cnt50)
+                }
+            } else if dtz < 0 {
+                if -dtz * 2 + cnt50 < 100 {
+                    -MAX_DTZ - dtz
+                } else {
+                    -MAX_DTZ / 2 + (-dtz + cnt50)
+                }
+            } else {
+                0
+            };
+            ranks.push(rank);
+        }
+
+        Some(ranks)
+    }
+
+    // without dtz tables just keep the moves with the best result
+    fn rank_root_moves_wdl(&self, b: &mut Board, tt: &TTable, moves: &[Move]) -> Option<Vec<i32>> {
+        let mut ranks = Vec::with_capacity(moves.len());
+
+        for m in moves {
+            b.make(m, tt);
+            if movegen::in_check_next(b) > 0 {
+                b.unmake(m, tt);
+                ranks.push(i32::MIN);
+                continue;
+            }
+
+            let wdl = self.probe_wdl(b);
+            b.unmake(m, tt);
+            ranks.push(match -wdl? {
+                Wdl::Loss => -MAX_DTZ,
+                Wdl::BlessedLoss => -MAX_DTZ + 101,
+                Wdl::Draw => 0,
+                Wdl::CursedWin => MAX_DTZ - 101,
+                Wdl::Win => MAX_DTZ,
+            });
+        }
+
+        Some(ranks)
+    }
+
+    // resolves captures (and pawn moves for dtz) first, the tables assume none of them are winning.
+    // the bool is true if the best result comes from a capture or pawn move
+    fn search(&self, b: &mut Board, check_zeroing: bool) -> Result<(Wdl, bool), ProbeError> {
+        let moves = legal_moves(b);
+        let mut best = Wdl::Loss;
+        let mut move_count = 0;
+
+        for m in &moves {
+            if m.xpiece == 12 && (!check_zeroing || m.piece > 1) {
+                continue;
+            }
+            move_count += 1;
+
+            b.make_no_hashing(m);
+            let result = self.search(b, false);
+            b.unmake_no_hashing(m);
+            let value = -result?.0;
+
+            if value > best {
+                best = value;
+                if value >= Wdl::Win {
+                    return Ok((value, true));
+                }
+            }
+        }
+
+        // every move was a capture so there is no need to look in the table
+        let no_more_moves = move_count > 0 && move_count == moves.len();
+        let value = if no_more_moves {
+            best
+        } else {
+            Wdl::from_value(self.probe_table(b, false, Wdl::Draw)?)
+        };
+
+        if best >= value {
+            Ok((best, best > Wdl::Draw || no_more_moves))
+        } else {
+            Ok((value, false))
+        }
+    }
+
+    fn dtz(&self, b: &mut Board) -> Result<i32, ProbeError> {
+        let (wdl, zeroing) = self.search(b, true)?;
+        if wdl == Wdl::Draw {
+            return Ok(0);
+        } else if zeroing {
+            return Ok(dtz_before_zeroing(wdl));
+        }
+
+        match self.probe_table(b, true, wdl) {
+            Ok(dtz) => {
+                let cursed = wdl == Wdl::CursedWin || wdl == Wdl::BlessedLoss;
+                Ok((dtz + if cursed { 100 } else { 0 }) * (wdl as i32).signum())
+            }
+            Err(ProbeError::ChangeStm) => self.dtz_from_children(b, wdl),
+            Err(e) => Err(e),
+        }
+    }
+
+    // the table is for the other side to move so take the best dtz after each move instead
+    fn dtz_from_children(&self, b: &mut Board, wdl: Wdl) -> Result<i32, ProbeError> {
+        let mut min_dtz = 0xFFFF;
+
+        for m in legal_moves(b) {
+            let zeroing = m.xpiece != 12 || m.piece < 2;
+
+            b.make_no_hashing(&m);
+            let result = if zeroing {
+                self.search(b, false).map(|(wdl, _)| -dtz_before_zeroing(wdl))
+            } else {
+                self.dtz(b).map(|dtz| -dtz)
+            };
+            let mated = result == Ok(1) && movegen::in_check_now(b) > 0 && legal_moves(b).is_empty();
+            b.unmake_no_hashing(&m);
+
+            let mut dtz = result?;
+            if mated {
+                min_dtz = 1;
+            }
+            if !zeroing {
+                dtz += dtz.signum();
+            }
+            if dtz < min_dtz && dtz.signum() == (wdl as i32).signum() {
+                min_dtz = dtz;
+            }
+        }
+
+        Ok(if min_dtz == 0xFFFF { -1 } else { min_dtz })
+    }
+
+    fn probe_table(&self, b: &Board, dtz: bool, wdl: Wdl) -> Result<i32, ProbeError> {
+        // kings only
+        if b.util[2].count_ones() == 2 {
+            return Ok(0);
+        }
+
+        let &index = self.keys.get(&material_key(&board_counts(b))).ok_or(ProbeError::Missing)?;
+        let table = &self.tables[index];
+        let file = if dtz { &table.dtz } else { &table.wdl };
+
+        table.probe(file.as_ref().ok_or(ProbeError::Missing)?, b, dtz, wdl)
+    }
+}
+
+// one piece of each colour on its own
+struct TbTable {
+    key: u64,
+    // key with the colours swapped
+    key2: u64,
+    piece_count: usize,
+    has_pawns: bool,
+    has_unique_pieces: bool,
+    // pawns of the leading colour then the other colour
+    pawn_count: [usize; 2],
+    // none until the files are mapped, a table without its dtz file can still be probed for wdl
+    wdl: Option<TbFile>,
+    dtz: Option<TbFile>,
+}
+
+impl TbTable {
+    // name is the material with white first, eg KRPvKR
+    fn new(name: &str) -> Option<TbTable> {
+        let (white, black) = name.split_once('v')?;
+        let mut counts = [0u8; 12];
+
+        for (colour, side) in [white, black].iter().enumerate() {
+            for c in side.chars() {
+                // same order as our piece indexes
+                let piece = "PNRBQK".find(c)?;
+                counts[piece * 2 + colour] += 1;
+            }
+        }
+
+        let piece_count = counts.iter().map(|&c| c as usize).sum();
+        if counts[10] != 1 || counts[11] != 1 || piece_count > TB_PIECES {
+            return None;
+        }
+
+        let swapped: [u8; 12] = std::array::from_fn(|i| counts[i ^ 1]);
+        let (white_pawns, black_pawns) = (counts[0] as usize, counts[1] as usize);
+        let white_leads = black_pawns == 0 || (white_pawns > 0 && black_pawns >= white_pawns);
+
+        Some(TbTable {
+            key: material_key(&counts),
+            key2: material_key(&swapped),
+            piece_count,
+            has_pawns: white_pawns + black_pawns > 0,
+            has_unique_pieces: counts[..10].contains(&1),
+            pawn_count: if white_leads { [white_pawns, black_pawns] } else { [black_pawns, white_pawns] },
+            wdl: None,
+            dtz: None,
+        })
+    }
+
+    fn probe(&self, f: &TbFile, b: &Board, dtz: bool, wdl: Wdl) -> Result<i32, ProbeError> {
+        let enc = encoding();
+        let mut squares = [0usize; TB_PIECES];
+        let mut pieces = [0u8; TB_PIECES];
+        let mut size = 0;
+        let mut lead_pawns = 0;
+        let mut tb_file = 0;
+
+        // tables are stored with the stronger side as white, and symmetric ones with white to move
+        let black_stronger = material_key(&board_counts(b)) != self.key;
+        let flip = black_stronger || (self.key == self.key2 && b.colour == 1);
+        let (flip_colour, flip_squares) = if flip { (8, 56) } else { (0, 0) };
+        let stm = flip as usize ^ b.colour;
+
+        // the leading pawns go first and decide which file the table is split on
+        if self.has_pawns {
+            let colour = ((f.pairs(0, 0).pieces[0] ^ flip_colour) >> 3) as usize;
+            lead_pawns = b.pieces[colour];
+
+            let mut bb = lead_pawns;
+            while bb > 0 {
+                squares[size] = bitscn_fw(&bb) ^ flip_squares;
+                size += 1;
+                bb &= bb - 1;
+            }
+
+            let lead = (0..size).max_by_key(|&i| enc.map_pawns[squares[i]]).unwrap();
+            squares.swap(0, lead);
+            tb_file = (squares[0] % 8).min(7 - squares[0] % 8);
+        }
+        let lead_pawns_cnt = size;
+
+        // symmetric pawnless tables are the same for either side to move
+        let symmetric = self.key == self.key2 && !self.has_pawns;
+        if dtz && !symmetric && f.pairs(0, tb_file).flags & FLAG_STM != stm as u8 {
+            return Err(ProbeError::ChangeStm);
+        }
+
+        for (piece, &bb) in b.pieces.iter().enumerate() {
+            let mut bb = bb & !lead_pawns;
+            while bb > 0 {
+                squares[size] = bitscn_fw(&bb) ^ flip_squares;
+                pieces[size] = TB_PIECE_CODE[piece] ^ flip_colour;
+                size += 1;
+                bb &= bb - 1;
+            }
+        }
+
+        // put the pieces in the order the table was encoded with
+        let d = f.pairs(stm, tb_file);
+        for i in lead_pawns_cnt..size - 1 {
+            if let Some(j) = (i + 1..size).find(|&j| d.pieces[i] == pieces[j]) {
+                pieces.swap(i, j);
+                squares.swap(i, j);
+            }
+        }
+
+        // mirror so the first piece is on files a-d
+        if squares[0] % 8 > 3 {
+            for sq in &mut squares[..size] {
+                *sq ^= 7;
+            }
+        }
+
+        let mut idx = if self.has_pawns {
+            let mut idx = enc.lead_pawn_idx[lead_pawns_cnt][squares[0]];
+            squares[1..lead_pawns_cnt].sort_by_key(|&sq| enc.map_pawns[sq]);
+            for (i, &sq) in squares.iter().enumerate().take(lead_pawns_cnt).skip(1) {
+                idx += enc.binomial[i][enc.map_pawns[sq]];
+            }
+            idx
+        } else {
+            // mirror onto ranks 1-4, then below the a1-h8 diagonal from the first piece thats off it
+            if squares[0] / 8 > 3 {
+                for sq in &mut squares[..size] {
+                    *sq ^= 56;
+                }
+            }
+            for i in 0..d.group_len[0] {
+                let off = off_diagonal(squares[i]);
+                if off == 0 {
+                    continue;
+                }
+                if off > 0 {
+                    for sq in &mut squares[i..size] {
+                        *sq = ((*sq >> 3) | (*sq << 3)) & 63;
+                    }
+                }
+                break;
+            }
+
+            if self.has_unique_pieces {
+                self.unique_pieces_index(&squares)
+            } else {
+                enc.map_kk[enc.map_a1d1d4[squares[0]]][squares[1]]
+            }
+        };
+
+        // the remaining groups of the same piece are encoded as combinations of the squares left
+        idx *= d.group_idx[0];
+        let mut group_start = d.group_len[0];
+        let mut remaining_pawns = self.has_pawns && self.pawn_count[1] > 0;
+        let mut next = 1;
+        while d.group_len[next] > 0 {
+            let len = d.group_len[next];
+            squares[group_start..group_start + len].sort();
+
+            let mut n = 0;
+            for i in 0..len {
+                let sq = squares[group_start + i];
+                let adjust = squares[..group_start].iter().filter(|&&s| sq > s).count();
+                n += enc.binomial[i + 1][sq - adjust - if remaining_pawns { 8 } else { 0 }];
+            }
+
+            remaining_pawns = false;
+            idx += n * d.group_idx[next];
+            group_start += len;
+            next += 1;
+        }
+
+        let value = d.decompress(&f.data, idx).ok_or(ProbeError::Missing)?;
+        if dtz {
+            Ok(f.map_dtz_score(tb_file, value, wdl))
+        } else {
+            Ok(value - 2)
+        }
+    }
+
+    // kings and the first unique piece, with the first piece already below or on the a1-h8 diagonal
+    fn unique_pieces_index(&self, sq: &[usize; TB_PIECES]) -> u64 {
+        let enc = encoding();
+        let rank = |s: usize| s / 8;
+        let adjust1 = (sq[1] > sq[0]) as usize;
+        let adjust2 = (sq[2] > sq[0]) as usize + (sq[2] > sq[1]) as usize;
+
+        let idx = if off_diagonal(sq[0]) != 0 {
+            (enc.map_a1d1d4[sq[0]] * 63 + (sq[1] - adjust1)) * 62 + sq[2] - adjust2
+        } else if off_diagonal(sq[1]) != 0 {
+            (6 * 63 + rank(sq[0]) * 28 + enc.map_b1h1h7[sq[1]]) * 62 + sq[2] - adjust2
+        } else if off_diagonal(sq[2]) != 0 {
+            6 * 63 * 62 + 4 * 28 * 62 + rank(sq[0]) * 7 * 28 + (rank(sq[1]) - adjust1) * 28 + enc.map_b1h1h7[sq[2]]
+        } else {
+            6 * 63 * 62 + 4 * 28 * 62 + 4 * 7 * 28 + rank(sq[0]) * 7 * 6 + (rank(sq[1]) - adjust1) * 6 + (rank(sq[2]) - adjust2)
+        };
+
+        idx as u64
+    }
+}
+
+// a table file mapped into memory, positions are offsets into data
+struct TbFile {
+    data: Mmap,
+    // one per side for each file the table is split on
+    pairs: Vec<PairsData>,
+    sides: usize,
+    // start of the dtz value maps
+    map: usize,
+}
+
+impl TbFile {
+    fn new(table: &TbTable, path: &Path, dtz: bool) -> Option<TbFile> {
+        let file = fs::File::open(path).ok()?;
+        // SAFETY: the file is opened read only and the map is only ever read through a shared slice.
+        // another process truncating or rewriting the file while it is mapped is undefined behaviour
+        // that no check here can prevent, the tables are never modified once generated so that is accepted
+        let data = unsafe { Mmap::map(&file) }.ok()?;
+        let magic = if dtz { DTZ_MAGIC } else { WDL_MAGIC };
+
+        let tb_file = if data.len() % 64 == 16 && data[..4] == magic { TbFile::read_header(table, data, dtz) } else { None };
+        if tb_file.is_none() {
+            println!("info string corrupt tablebase file {}", path.display());
+        }
+        tb_file
+    }
+
+    // none if any value in the header doesnt fit the table or points outside the file
+    fn read_header(table: &TbTable, data: Mmap, dtz: bool) -> Option<TbFile> {
+        let flags = data[4];
+        if (flags & FILE_HAS_PAWNS != 0) != table.has_pawns || (!dtz && (flags & FILE_SPLIT != 0) != (table.key != table.key2)) {
+            return None;
+        }
+
+        let sides = if !dtz && table.key != table.key2 { 2 } else { 1 };
+        let files = if table.has_pawns { 4 } else { 1 };
+        let both_pawns = table.has_pawns && table.pawn_count[1] > 0;
+        let mut pairs = vec![PairsData::default(); files * sides];
+        let mut pos = 5;
+
+        // the order pieces were encoded in for each side
+        for f in 0..files {
+            let second = if both_pawns { byte(&data, pos + 1) } else { 0xFF };
+            let order = [[byte(&data, pos) & 0xF, second & 0xF], [byte(&data, pos) >> 4, second >> 4]];
+            pos += 1 + both_pawns as usize;
+
+            for k in 0..table.piece_count {
+                for (i, d) in pairs[f * sides..(f + 1) * sides].iter_mut().enumerate() {
+                    d.pieces[k] = if i == 1 { byte(&data, pos) >> 4 } else { byte(&data, pos) & 0xF };
+                }
+                pos += 1;
+            }
+
+            for (i, d) in pairs[f * sides..(f + 1) * sides].iter_mut().enumerate() {
+                d.set_groups(table, order[i], f);
+            }
+        }
+        pos += pos & 1;
+
+        // each side has to list the pieces in the name, with the leading pawns first
+        for d in &pairs {
+            let mut counts = [0u8; 12];
+            for &code in &d.pieces[..table.piece_count] {
+                counts[TB_PIECE_CODE.iter().position(|&c| c == code)?] += 1;
+            }
+            if material_key(&counts) != table.key || d.pieces[..table.pawn_count[0]].iter().any(|&code| code & 7 != 1) {
+                return None;
+            }
+        }
+
+        for d in &mut pairs {
+            pos = d.set_sizes(&data, pos)?;
+        }
+
+        let map = pos;
+        if dtz {
+            for d in &mut pairs {
+                if d.flags & FLAG_MAPPED == 0 {
+                    continue;
+                }
+
+                if d.flags & FLAG_WIDE > 0 {
+                    pos += pos & 1;
+                    for i in 0..4 {
+                        d.map_idx[i] = u16::try_from((pos - map) / 2 + 1).ok()?;
+                        pos += 2 * read_u16(&data, pos) as usize + 2;
+                    }
+                } else {
+                    for i in 0..4 {
+                        d.map_idx[i] = u16::try_from(pos - map + 1).ok()?;
+                        pos += byte(&data, pos) as usize + 1;
+                    }
+                }
+            }
+            pos += pos & 1;
+        }
+
+        // sizes come straight from the file so can be anything
+        let region = |count: u64, size: u64| count.checked_mul(size).and_then(|len| usize::try_from(len).ok());
+        for d in &mut pairs {
+            d.sparse_index = pos;
+            pos = pos.checked_add(region(d.sparse_index_size, 6)?)?;
+        }
+        for d in &mut pairs {
+            d.block_length = pos;
+            pos = pos.checked_add(region(d.block_length_size, 2)?)?;
+        }
+        for d in &mut pairs {
+            pos = pos.checked_add(0x3F)? & !0x3F;
+            d.data = pos;
+            pos = pos.checked_add(region(d.num_blocks as u64, d.size_of_block)?)?;
+        }
+
+        if pos > data.len() {
+            return None;
+        }
+
+        Some(TbFile { data, pairs, sides, map })
+    }
+
+    fn pairs(&self, stm: usize, file: usize) -> &PairsData {
+        &self.pairs[file * self.sides + stm % self.sides]
+    }
+
+    // dtz values can be stored through a map, and in moves rather than plies where that loses nothing
+    fn map_dtz_score(&self, file: usize, value: i32, wdl: Wdl) -> i32 {
+        const WDL_MAP: [usize; 5] = [1, 3, 0, 2, 0];
+        let d = self.pairs(0, file);
+        let mut value = value;
+
+        if d.flags & FLAG_MAPPED > 0 {
+            let i = d.map_idx[WDL_MAP[(wdl as i32 + 2) as usize]] as usize + value as usize;
+            value = if d.flags & FLAG_WIDE > 0 {
+                read_u16(&self.data, self.map + 2 * i) as i32
+            } else {
+                byte(&self.data, self.map + i) as i32
+            };
+        }
+
+        if (wdl == Wdl::Win && d.flags & FLAG_WIN_PLIES == 0)
+            || (wdl == Wdl::Loss && d.flags & FLAG_LOSS_PLIES == 0)
+            || wdl == Wdl::CursedWin
+            || wdl == Wdl::BlessedLoss
+        {
+            value *= 2;
+        }
+
+        value + 1
+    }
+}
+
+// a table compressed into blocks of huffman coded symbols, each symbol expanding to a pair of smaller symbols
+#[derive(Clone, Default)]
+struct PairsData {
+    flags: u8,
+    size_of_block: u64,
+    // positions between sparse index entries
+    span: u64,
+    num_blocks: u32,
+    min_sym_len: u8,
+    lowest_sym: usize,
+    btree: usize,
+    sparse_index: usize,
+    sparse_index_size: u64,
+    block_length: usize,
+    block_length_size: u64,
+    data: usize,
+    // lowest code of each length, left aligned
+    base64: Vec<u64>,
+    // how many values each symbol expands to, less one
+    symlen: Vec<u8>,
+    pieces: [u8; TB_PIECES],
+    // pieces are split into groups, the first is the kings and leading pieces and the rest are identical pieces
+    group_idx: [u64; TB_PIECES + 1],
+    group_len: [usize; TB_PIECES + 1],
+    map_idx: [u16; 4],
+}
+
+impl PairsData {
+    fn set_groups(&mut self, table: &TbTable, order: [u8; 2], file: usize) {
+        let enc = encoding();
+        let mut n = 0;
+        let mut first_len: i32 = if table.has_pawns { 0 } else if table.has_unique_pieces { 3 } else { 2 };
+        self.group_len[0] = 1;
+
+        for i in 1..table.piece_count {
+            first_len -= 1;
+            if first_len > 0 || self.pieces[i] == self.pieces[i - 1] {
+                self.group_len[n] += 1;
+            } else {
+                n += 1;
+                self.group_len[n] = 1;
+            }
+        }
+        n += 1;
+        self.group_len[n] = 0;
+
+        // the leading group and the other sides pawns can come anywhere in the index order
+        let both_pawns = table.has_pawns && table.pawn_count[1] > 0;
+        let mut next = if both_pawns { 2 } else { 1 };
+        let mut free_squares = 64 - self.group_len[0] - if both_pawns { self.group_len[1] } else { 0 };
+        let mut idx = 1;
+        let mut k = 0;
+
+        while next < n || k == order[0] as usize || k == order[1] as usize {
+            if k == order[0] as usize {
+                self.group_idx[0] = idx;
+                idx *= if table.has_pawns {
+                    enc.lead_pawns_size[self.group_len[0]][file]
+                } else if table.has_unique_pieces {
+                    31332
+                } else {
+                    462
+                };
+            } else if k == order[1] as usize {
+                self.group_idx[1] = idx;
+                idx *= enc.binomial[self.group_len[1]][48 - self.group_len[0]];
+            } else {
+                self.group_idx[next] = idx;
+                idx *= enc.binomial[self.group_len[next]][free_squares];
+                free_squares -= self.group_len[next];
+                next += 1;
+            }
+            k += 1;
+        }
+
+        self.group_idx[n] = idx;
+    }
+
+    fn set_sizes(&mut self, data: &[u8], mut pos: usize) -> Option<usize> {
+        self.flags = byte(data, pos);
+        pos += 1;
+
+        // the whole table is one value
+        if self.flags & FLAG_SINGLE_VALUE > 0 {
+            self.min_sym_len = byte(data, pos);
+            return Some(pos + 1);
+        }
+
+        // the last group index is the size of the table
+        let n = self.group_len.iter().position(|&len| len == 0).unwrap_or(TB_PIECES);
+        let tb_size = self.group_idx[n];
+        let (block_shift, span_shift) = (byte(data, pos), byte(data, pos + 1));
+        let padding = byte(data, pos + 2);
+        self.num_blocks = read_u32(data, pos + 3);
+        let max_sym_len = byte(data, pos + 7);
+        self.min_sym_len = byte(data, pos + 8);
+        pos += 9;
+
+        // codes are topped up 32 bits at a time so none can be longer than that
+        if block_shift > 32 || span_shift > 32 || max_sym_len > 32 || max_sym_len < self.min_sym_len || self.min_sym_len == 0 {
+            return None;
+        }
+        self.size_of_block = 1 << block_shift;
+        self.span = 1 << span_shift;
+        self.sparse_index_size = tb_size.div_ceil(self.span);
+        self.block_length_size = self.num_blocks as u64 + padding as u64;
+
+        // symbols of each code length start from lowest_sym, base64 is the lowest code of each length
+        self.lowest_sym = pos;
+        let lengths = (max_sym_len - self.min_sym_len + 1) as usize;
+        self.base64 = vec![0; lengths];
+        for i in (0..lengths - 1).rev() {
+            let lowest = read_u16(data, pos + 2 * i) as u64;
+            let next_lowest = read_u16(data, pos + 2 * (i + 1)) as u64;
+            self.base64[i] = self.base64[i + 1].wrapping_add(lowest).wrapping_sub(next_lowest) / 2;
+        }
+        for (i, base) in self.base64.iter_mut().enumerate() {
+            *base <<= 64 - i - self.min_sym_len as usize;
+        }
+        pos += lengths * 2;
+
+        let symbols = read_u16(data, pos) as usize;
+        pos += 2;
+        self.btree = pos;
+        self.symlen = vec![0; symbols];
+        let mut visited = vec![false; symbols];
+        for sym in 0..symbols {
+            if !visited[sym] {
+                self.symlen[sym] = self.set_symlen(data, sym, &mut visited);
+            }
+        }
+
+        // every pair has to expand to strictly shorter symbols, otherwise decompressing could loop forever
+        for sym in 0..symbols {
+            let (left, right) = self.children(data, sym);
+            if right == 0xFFF {
+                continue;
+            }
+            if left >= symbols || right >= symbols
+                || self.symlen[sym] as u16 != self.symlen[left] as u16 + self.symlen[right] as u16 + 1
+            {
+                return None;
+            }
+        }
+
+        Some(pos + symbols * 3 + (symbols & 1))
+    }
+
+    fn set_symlen(&mut self, data: &[u8], sym: usize, visited: &mut [bool]) -> u8 {
+        visited[sym] = true;
+
+        let (left, right) = self.children(data, sym);
+        if right == 0xFFF || left >= visited.len() || right >= visited.len() {
+            return 0;
+        }
+
+        for child in [left, right] {
+            if !visited[child] {
+                self.symlen[child] = self.set_symlen(data, child, visited);
+            }
+        }
+
+        self.symlen[left].wrapping_add(self.symlen[right]).wrapping_add(1)
+    }
+
+    // 12 bits each packed into 3 bytes, leaves have no right child and the value in left
+    fn children(&self, data: &[u8], sym: usize) -> (usize, usize) {
+        let lr = self.btree + sym * 3;
+        let (b0, b1, b2) = (byte(data, lr) as usize, byte(data, lr + 1) as usize, byte(data, lr + 2) as usize);
+
+        (((b1 & 0xF) << 8) | b0, (b2 << 4) | (b1 >> 4))
+    }
+
+    // none if the sparse index or block lengths lead outside the table
+    fn decompress(&self, data: &[u8], idx: u64) -> Option<i32> {
+        if self.flags & FLAG_SINGLE_VALUE > 0 {
+            return Some(self.min_sym_len as i32);
+        }
+
+        // the sparse index gives the block and offset of a position near idx, then step to idx itself
+        let sparse = self.sparse_index + (idx / self.span) as usize * 6;
+        let mut block = read_u32(data, sparse) as usize;
+        let mut offset = read_u16(data, sparse + 4) as i64 + (idx % self.span) as i64 - (self.span / 2) as i64;
+        let block_length = |block: usize| read_u16(data, self.block_length + 2 * block) as i64;
+
+        while offset < 0 {
+            block = block.checked_sub(1)?;
+            offset += block_length(block) + 1;
+        }
+        while offset > block_length(block) {
+            offset -= block_length(block) + 1;
+            block += 1;
+        }
+        if block >= self.num_blocks as usize {
+            return None;
+        }
+
+        // codes are read big endian, 64 bits at a time
+        let mut ptr = self.data + block * self.size_of_block as usize;
+        let mut buf64 = read_u64_be(data, ptr);
+        let mut buf64_size = 64;
+        ptr += 8;
+
+        let mut sym;
+        loop {
+            let mut len = 0;
+            while len + 1 < self.base64.len() && buf64 < self.base64[len] {
+                len += 1;
+            }
+
+            let code = (buf64 - self.base64[len]) >> (64 - len - self.min_sym_len as usize);
+            sym = (code as u16).wrapping_add(read_u16(data, self.lowest_sym + 2 * len)) as usize;
+            let sym_len = *self.symlen.get(sym)? as i64;
+            if offset < sym_len + 1 {
+                break;
+            }
+
+            offset -= sym_len + 1;
+            len += self.min_sym_len as usize;
+            buf64 <<= len;
+            buf64_size -= len;
+            if buf64_size <= 32 {
+                buf64_size += 32;
+                buf64 |= (read_u32_be(data, ptr) as u64) << (64 - buf64_size);
+                ptr += 4;
+            }
+        }
+
+        // expand the symbol down to the value at offset
+        while self.symlen[sym] > 0 {
+            let (left, right) = self.children(data, sym);
+            let left_len = self.symlen[left] as i64;
+            if offset < left_len + 1 {
+                sym = left;
+            } else {
+                offset -= left_len + 1;
+                sym = right;
+            }
+        }
+
+        Some(self.children(data, sym).0 as i32)
+    }
+}
+
+// lookup tables for turning piece squares into a table index
+struct Encoding {
+    // squares below the a1-h8 diagonal
+    map_b1h1h7: [usize; 64],
+    // squares in the a1-d1-d4 triangle, below the diagonal first
+    map_a1d1d4: [usize; 64],
+    // the 462 legal king pairs with the first king in the triangle
+    map_kk: [[u64; 64]; 10],
+    binomial: [[u64; 64]; TB_PIECES],
+    map_pawns: [usize; 64],
+    lead_pawn_idx: [[u64; 64]; TB_PIECES - 1],
+    lead_pawns_size: [[u64; 4]; TB_PIECES - 1],
+}
+
+static ENCODING: OnceLock<Encoding> = OnceLock::new();
+
+fn encoding() -> &'static Encoding {
+    ENCODING.get_or_init(Encoding::new)
+}
+
+impl Encoding {
+    fn new() -> Encoding {
+        let mut enc = Encoding {
+            map_b1h1h7: [0; 64],
+            map_a1d1d4: [0; 64],
+            map_kk: [[0; 64]; 10],
+            binomial: [[0; 64]; TB_PIECES],
+            map_pawns: [0; 64],
+            lead_pawn_idx: [[0; 64]; TB_PIECES - 1],
+            lead_pawns_size: [[0; 4]; TB_PIECES - 1],
+        };
+
+        for (code, sq) in (0..64).filter(|&sq| off_diagonal(sq) < 0).enumerate() {
+            enc.map_b1h1h7[sq] = code;
+        }
+
+        // d4 is the last square in the triangle
+        let triangle: Vec<usize> = (0..=27).filter(|&sq| sq % 8 <= 3).collect();
+        let below = triangle.iter().filter(|&&sq| off_diagonal(sq) < 0);
+        let on = triangle.iter().filter(|&&sq| off_diagonal(sq) == 0);
+        for (code, &sq) in below.chain(on).enumerate() {
+            enc.map_a1d1d4[sq] = code;
+        }
+
+        // both kings on the diagonal go last, and the second king can be mirrored below it if the first is on it
+        let mut code = 0;
+        let mut both_on_diagonal = Vec::new();
+        for idx in 0..10 {
+            for &sq1 in triangle.iter().filter(|&&sq| enc.map_a1d1d4[sq] == idx && (idx > 0 || sq == 1)) {
+                for sq2 in 0..64 {
+                    if king_distance(sq1, sq2) <= 1 || (off_diagonal(sq1) == 0 && off_diagonal(sq2) > 0) {
+                        continue;
+                    } else if off_diagonal(sq1) == 0 && off_diagonal(sq2) == 0 {
+                        both_on_diagonal.push((idx, sq2));
+                    } else {
+                        enc.map_kk[idx][sq2] = code;
+                        code += 1;
+                    }
+                }
+            }
+        }
+        for (idx, sq2) in both_on_diagonal {
+            enc.map_kk[idx][sq2] = code;
+            code += 1;
+        }
+
+        enc.binomial[0][0] = 1;
+        for n in 1..64 {
+            for k in 0..TB_PIECES.min(n + 1) {
+                let with = if k > 0 { enc.binomial[k - 1][n - 1] } else { 0 };
+                let without = if k < n { enc.binomial[k][n - 1] } else { 0 };
+                enc.binomial[k][n] = with + without;
+            }
+        }
+
+        // pawns are numbered from the edge files in, so the leading pawn is the one closest to the edge
+        let mut available = 48;
+        for lead in 1..TB_PIECES - 1 {
+            for file in 0..4 {
+                let mut idx = 0;
+                for rank in 1..7 {
+                    let sq = rank * 8 + file;
+                    if lead == 1 {
+                        enc.map_pawns[sq] = available - 1;
+                        enc.map_pawns[sq ^ 7] = available - 2;
+                        available -= 2;
+                    }
+                    enc.lead_pawn_idx[lead][sq] = idx;
+                    idx += enc.binomial[lead - 1][enc.map_pawns[sq]];
+                }
+                enc.lead_pawns_size[lead][file] = idx;
+            }
+        }
+
+        enc
+    }
+}
+
+// positive above the a1-h8 diagonal
+fn off_diagonal(sq: usize) -> i32 {
+    (sq / 8) as i32 - (sq % 8) as i32
+}
+
+fn king_distance(sq1: usize, sq2: usize) -> usize {
+    (sq1 / 8).abs_diff(sq2 / 8).max((sq1 % 8).abs_diff(sq2 % 8))
+}
+
+fn dtz_before_zeroing(wdl: Wdl) -> i32 {
+    match wdl {
+        Wdl::Win => 1,
+        Wdl::CursedWin => 101,
+        Wdl::Draw => 0,
+        Wdl::BlessedLoss => -101,
+        Wdl::Loss => -1,
+    }
+}
+
+fn legal_moves(b: &mut Board) -> Vec<Move> {
+    movegen::gen_moves(b).into_iter().filter(|m| {
+        b.make_no_hashing(m);
+        let legal = movegen::in_check_next(b) == 0;
+        b.unmake_no_hashing(m);
+        legal
+    }).collect()
+}
+
+fn board_counts(b: &Board) -> [u8; 12] {
+    b.pieces.map(|bb| bb.count_ones() as u8)
+}
+
+// 4 bits for the count of each piece, so every position with the same material has the same key
+fn material_key(counts: &[u8; 12]) -> u64 {
+    counts.iter().enumerate().fold(0, |key, (piece, &count)| key | (count as u64) << (piece * 4))
+}
+
+fn byte(data: &[u8], pos: usize) -> u8 {
+    data.get(pos).copied().unwrap_or(0)
+}
+
+fn read_u16(data: &[u8], pos: usize) -> u16 {
+    u16::from_le_bytes([byte(data, pos), byte(data, pos + 1)])
+}
+
+fn read_u32(data: &[u8], pos: usize) -> u32 {
+    u32::from_le_bytes(std::array::from_fn(|i| byte(data, pos + i)))
+}
+
+fn read_u32_be(data: &[u8], pos: usize) -> u32 {
+    u32::from_be_bytes(std::array::from_fn(|i| byte(data, pos + i)))
+}
+
+fn read_u64_be(data: &[u8], pos: usize) -> u64 {
+    u64::from_be_bytes(std::array::from_fn(|i| byte(data, pos + i)))
+}
+
+
+#[test]
+fn tb_encoding_test() {
+    let enc = encoding();
+
+    assert_eq!(enc.map_b1h1h7.iter().max(), Some(&27));
+    assert_eq!(enc.map_a1d1d4.iter().max(), Some(&9));
+    // every legal placement of the kings once the board symmetries are taken out
+    assert_eq!(enc.map_kk.iter().flatten().max(), Some(&461));
+    assert_eq!(enc.binomial[2][6], 15);
+    // a single leading pawn can be on any of 6 ranks of its file
+    assert_eq!(enc.lead_pawns_size[1], [6; 4]);
+    assert_eq!(enc.map_pawns.iter().max(), Some(&47));
+}
+
+#[test]
+fn tb_table_name_test() {
+    let table = TbTable::new("KRPvKR").unwrap();
+    let white = Board::new_from_fen("8/8/4k3/8/3KP3/8/1R6/6r1 w - - 0 1");
+    let black = Board::new_from_fen("6R1/1r6/8/3kp3/8/4K3/8/8 b - - 0 1");
+
+    assert_eq!(table.piece_count, 5);
+    assert_eq!(table.pawn_count, [1, 0]);
+    assert!(table.has_pawns && table.has_unique_pieces);
+    assert_eq!(material_key(&board_counts(&white)), table.key);
+    assert_eq!(material_key(&board_counts(&black)), table.key2);
+
+    assert!(TbTable::new("KKvK").is_none());
+    assert!(TbTable::new("KXvK").is_none());
+
+    // nothing to read, so nothing can be probed
+    let tb = Tablebases::new_from_path("/nonexistent/syzygy");
+    let mut b = Board::new_from_fen("8/8/4k3/8/8/8/1R6/4K3 w - - 0 1");
+    assert_eq!(tb.max_pieces(), 0);
+    assert!(!tb.can_probe(&b));
+    assert_eq!(tb.probe_wdl(&mut b), None);
+}
+
+// the official files are huffman coded with pair symbols, which nothing small enough to write by hand
+// exercises. only results the rules decide are checked, the odd dtz of a win comes out exact even from a
+// table stored in moves
+#[test]
+#[ignore = "needs the official KQvK, KRvK, KPvK, KRvKP and KPvKP tables copied into fixtures/syzygy"]
+fn tb_probe_test() {
+    let tb = Tablebases::new_from_path(concat!(env!("CARGO_MANIFEST_DIR"), "/fixtures/syzygy"));
+    assert_eq!((tb.len(), tb.max_pieces()), (5, 4));
+
+    for (fen, wdl, dtz) in [
+        // mate in one, and the mated side
+        ("k7/8/1K6/8/8/8/7Q/8 w - - 0 1", Wdl::Win, Some(1)),
+        ("k6Q/8/1K6/8/8/8/8/8 b - - 0 1", Wdl::Loss, Some(-1)),
+        // the same with the colours swapped
+        ("8/7q/8/8/8/1k6/8/K7 b - - 0 1", Wdl::Win, Some(1)),
+        // the queen is lost straight away
+        ("8/8/8/8/8/2kQ4/8/6K1 b - - 0 1", Wdl::Draw, Some(0)),
+        // kb6 kb8 rh8#, as in mate_score_test
+        ("k7/8/2K5/8/8/8/8/7R w - - 0 1", Wdl::Win, Some(3)),
+        // the king in front of its pawn on the sixth rank wins whoever is to move, the pawn can push on the third ply
+        ("4k3/8/4K3/4P3/8/8/8/8 w - - 0 1", Wdl::Win, Some(3)),
+        ("4k3/8/4K3/4P3/8/8/8/8 b - - 0 1", Wdl::Loss, None),
+        // a rook pawn cant get the king out of the corner
+        ("k7/8/8/K7/P7/8/8/8 w - - 0 1", Wdl::Draw, Some(0)),
+        // promoting resets the counter
+        ("8/4P3/8/4K3/8/8/8/k7 w - - 0 1", Wdl::Win, Some(1)),
+        // rc8# with the pawn a move from promoting
+        ("k7/8/1K6/8/8/8/7p/2R5 w - - 0 1", Wdl::Win, Some(1)),
+        // pawns on both sides, promoting with mate for white and then for black
+        ("k7/2P5/1K6/8/8/8/7p/8 w - - 0 1", Wdl::Win, Some(1)),
+        ("8/7P/8/8/8/1k6/2p5/K7 b - - 0 1", Wdl::Win, Some(1)),
+    ] {
+        let mut b = Board::new_from_fen(fen);
+        assert_eq!(tb.probe_wdl(&mut b), Some(wdl), "{}", fen);
+        if dtz.is_some() {
+            assert_eq!(tb.probe_dtz(&mut b), dtz, "{}", fen);
+        }
+    }
+}
+
+#[test]
+fn tb_corrupt_header_test() {
+    // a knight ending stored as every position drawn, with the given block size and longest code length
+    let write = |dir: &Path, block_shift: u8, max_sym_len: u8| {
+        let mut data = WDL_MAGIC.to_vec();
+        data.extend([FILE_SPLIT, 0, 0x66, 0xEE, 0x22, 0]);
+        for _ in 0..2 {
+            data.extend([0, block_shift, 10, 0, 1, 0, 0, 0, max_sym_len, 1]);
+            data.extend(vec![0; 2 * max_sym_len as usize]);
+            // a single symbol, the leaf for a draw
+            data.extend([1, 0, 2, 0xF0, 0xFF, 0]);
+        }
+        // 31332 positions in spans of 1024, all in the one block
+        for _ in 0..2 * 31 {
+            data.extend([0, 0, 0, 0, 0, 2]);
+        }
+        data.extend([0xFF, 0x03, 0xFF, 0x03]);
+        data.resize(64 * 16 + 16, 0);
+
+        fs::create_dir_all(dir).unwrap();
+        fs::write(dir.join("KNvK.rtbw"), data).unwrap();
+    };
+    let dir = env::temp_dir().join("rustinator_tb_corrupt_header_test");
+
+    write(&dir, 5, 8);
+    let tb = Tablebases::new_from_path(dir.to_str().unwrap());
+    assert_eq!(tb.len(), 1);
+    assert_eq!(tb.probe_wdl(&mut Board::new_from_fen("8/8/8/3k4/8/8/2N5/4K3 w - - 0 1")), Some(Wdl::Draw));
+
+    // codes longer than the 32 bits topped up at a time, and blocks too big to be in the file
+    for (block_shift, max_sym_len) in [(5, 40), (60, 8)] {
+        write(&dir, block_shift, max_sym_len);
+        assert_eq!(Tablebases::new_from_path(dir.to_str().unwrap()).len(), 0);
+    }
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+#[ignore = "needs the official KRvK tables copied into fixtures/syzygy"]
+fn tb_root_rank_test() {
+    use std::time::Instant;
+    use crate::move_ordering::RootOrderList;
+    use crate::search::{Search, SearchSignals, TimeControl};
+
+    let tb = Tablebases::new_from_path(concat!(env!("CARGO_MANIFEST_DIR"), "/fixtures/syzygy"));
+    let tt = TTable::new();
+    // the rook is attacked, only moving it away keeps the win
+    let mut b = Board::new_from_fen("8/8/8/8/8/8/1k6/R5K1 w - - 0 1");
+    b.hash = b.get_hash(&tt);
+
+    let mut root = RootOrderList::new(&b);
+    let moves: Vec<Move> = root.now.iter().map(|(m, _)| *m).collect();
+    let ranks = tb.rank_root_moves(&mut b, &tt, &moves).unwrap();
+    root.retain_best(&ranks);
+
+    let mut kept: Vec<String> = root.now.iter().map(|(m, _)| m.as_uci_string()).collect();
+    kept.sort();
+    assert_eq!(kept, ["a1a4", "a1a5", "a1a6", "a1a7", "a1a8", "a1d1", "a1e1", "a1f1"]);
+    for (m, _) in &root.now {
+        b.make(m, &tt);
+        assert_eq!(tb.probe_wdl(&mut b), Some(Wdl::Loss));
+        b.unmake(m, &tt);
+    }
+
+    // the search only ever plays one of them
+    let signals = SearchSignals::new();
+    let mut tc = TimeControl::new_unlimited(Instant::now());
+    tc.max_depth = 4;
+    let mut search = Search::new(b.clone(), &tt, tc, &signals);
+    search.tb = Some(&tb);
+    let pv = search.iterative_deepening_search();
+    assert!(kept.contains(&pv[0].as_uci_string()));
+}
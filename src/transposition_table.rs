@@ -1,45 +1,73 @@
-use std::ops::DerefMut;
+use std::sync::atomic::{ AtomicU64, AtomicU8, Ordering };
 
 use rand::prelude::*;
 
 use crate::{ Board, Move};
 use crate::eval;
 
-const TTABLE_SIZE: usize = 1048576; // 2^20
-const TTABLE_INDEX_MASK: u64 = 0xFFFFF; 
+pub const DEFAULT_TTABLE_MB: usize = 32;
+// entries per bucket, a hash can be stored in any slot of its bucket
+const BUCKET_SIZE: usize = 4;
+// how many plies of depth an entry loses for each search it is out of date
+const AGE_PENALTY: i32 = 8;
+// ages are packed into 6 bits
+const AGE_MASK: u8 = 0x3F;
 
+type Bucket = [AtomicEntry; BUCKET_SIZE];
 
-#[derive(Clone)]
+
+// shared between all search threads, entries are packed into atomics so no locking is needed
 pub struct TTable {
     pub zorbist_array: [u64; 781],
-    pub ttable: Box<[TEntry]>,
-    pub hheuristic: [[i32; 64]; 12],
-    pub hit_count: u64,
-    pub miss_count: u64,
+    pub ttable: Box<[Bucket]>,
+    // generation counter, bumped every search so old entries can be replaced first
+    age: AtomicU8,
 }
 
 impl TTable {
     pub fn new() -> TTable {
-        let tentry = TEntry {
-            hash: 0,
-            best_move: None,
-            depth: 0,
-            score: 0,
-            node_type: NodeType::Pv,
-        };
-        
-        let hheuristic = [[0; 64]; 12];
+        TTable::new_with_mb(DEFAULT_TTABLE_MB)
+    }
 
-        let mut ttable = vec![tentry; TTABLE_SIZE].into_boxed_slice();
+    pub fn new_with_mb(size: usize) -> TTable {
+        TTable::new_with_keys(size, TTable::init_zorbist_array())
+    }
+
+    // an empty table of a new size with the same zorbist keys, so hashes of existing boards stay valid
+    pub fn resize(&self, size: usize) -> TTable {
+        TTable::new_with_keys(size, self.zorbist_array)
+    }
+
+    fn new_with_keys(size: usize, zorbist_array: [u64; 781]) -> TTable {
+        // as many buckets as fit in the given size, index() maps a hash onto any table length
+        let buckets = (size * 1024 * 1024 / std::mem::size_of::<Bucket>()).max(1);
+
+        let ttable = (0..buckets).map(|_| Default::default()).collect();
         TTable { 
-            zorbist_array: TTable::init_zorbist_array(), 
+            zorbist_array, 
             ttable,
-            hheuristic,
-            hit_count: 0,
-            miss_count: 0
+            age: AtomicU8::new(0),
         }
     }
 
+    // scales the hash into 0..len, which unlike masking works for any size table
+    #[inline(always)]
+    fn index(&self, hash: u64) -> usize {
+        ((hash as u128 * self.ttable.len() as u128) >> 64) as usize
+    }
+
+    // wipes the entries but keeps the same zorbist keys so existing board hashes stay valid
+    pub fn clear(&self) {
+        for entry in self.ttable.iter().flatten() {
+            entry.store(0, 0);
+        }
+        self.age.store(0, Ordering::Relaxed);
+    }
+
+    pub fn new_search(&self) {
+        let age = self.age.load(Ordering::Relaxed);
+        self.age.store((age + 1) & AGE_MASK, Ordering::Relaxed);
+    }
 
     /*
     --- zorbist array indexing ---
@@ -59,101 +87,297 @@ impl TTable {
         zorbist_array
     }
 
-    pub fn get(&mut self, hash: u64, curr_depth: u8, mate_dist: i32, alpha: i32, beta: i32) -> Option<i32> {
-        let entry = self.ttable[(hash & TTABLE_INDEX_MASK) as usize]; 
-        
-        if entry.hash == 0 || entry.depth < curr_depth {
-            return None;
-        } else if entry.hash != hash {
-            self.miss_count += 1;
+    pub fn get(&self, hash: u64, curr_depth: u8, ply: usize, alpha: i32, beta: i32) -> Option<i32> {
+        let entry = self.probe(hash)?;
+
+        if entry.depth < curr_depth {
             return None;
         }
 
+        let score = score_from_tt(entry.score, ply);
         match entry.node_type {
-            NodeType::Pv => {
-                self.hit_count += 1;
-                
-                if entry.score <= eval::CHECKMATE {
-                    // adjust the stored checkmate score for the current mate distance
-                    Some(entry.score - (entry.score - mate_dist*eval::CHECKMATE))
-                } else if entry.score >= -eval::CHECKMATE {
-                    Some(entry.score - (entry.score + mate_dist*eval::CHECKMATE))
-                } else {
-                    Some(entry.score)
-                }
-            }
+            NodeType::Pv => Some(score),
             NodeType::Alpha => {
-                if entry.score <= alpha {
-                    self.hit_count += 1;
+                if score <= alpha {
                     Some(alpha)
                 } else {
                     None
                 }
             }
             NodeType::Beta => {
-                if entry.score >= beta{
-                    self.hit_count += 1;
+                if score >= beta{
                     Some(beta)
                 } else {
                     None
                 }
             }
-
-            _ => None
         }
     }
 
     #[inline(always)]
-    pub fn get_bestmove(&self, hash: u64) -> Option<Move> {
-        let entry = self.ttable[(hash & TTABLE_INDEX_MASK) as usize]; 
-        
-        if entry.hash != hash {
-            None
-        } else {
-            entry.best_move
-        }
+    fn probe(&self, hash: u64) -> Option<TEntry> {
+        self.ttable[self.index(hash)].iter()
+            .map(AtomicEntry::load)
+            .find(|e| e.hash == hash && hash != 0)
     }
 
+    // the whole entry for a position, with any mate or tablebase score made relative to the root again
+    pub fn get_entry(&self, hash: u64, ply: usize) -> Option<TEntry> {
+        let mut entry = self.probe(hash)?;
+        entry.score = score_from_tt(entry.score, ply);
+        Some(entry)
+    }
+
+    // the tt only keeps the squares of the move so the rest is filled in from the board
     #[inline(always)]
-    pub fn insert(&mut self, entry: TEntry) {
-        if entry.node_type != NodeType::Pv {
-            self.ttable[(entry.hash & TTABLE_INDEX_MASK) as usize] = entry;
+    pub fn get_bestmove(&self, b: &Board) -> Option<Move> {
+        self.probe(b.hash)?.get_bestmove(b)
+    }
+
+    pub fn insert(&self, mut entry: TEntry) {
+        let age = self.age.load(Ordering::Relaxed);
+        let bucket = &self.ttable[self.index(entry.hash)];
+        entry.age = age;
+
+        // position is already stored, only overwrite it with something at least about as deep.
+        // among entries of about the same depth an exact score isnt replaced by a bound
+        if let Some(slot) = bucket.iter().find(|e| e.load().hash == entry.hash) {
+            let old = slot.load();
+            if entry.best_move == 0 {
+                entry.best_move = old.best_move;
+            }
+
+            let replace = old.age != age
+                || entry.depth > old.depth
+                || (entry.depth + 2 >= old.depth && (entry.node_type == NodeType::Pv || old.node_type != NodeType::Pv));
+            if replace {
+                slot.store(entry.hash, entry.pack());
+            }
+            return;
         }
+
+        // otherwise replace the least useful entry, empty then shallow and old ones first
+        let replace = bucket.iter()
+            .min_by_key(|e| e.load().replace_value(age))
+            .unwrap();
+        replace.store(entry.hash, entry.pack());
     }
 
+    // permille of the table in use, estimated from the start of the table
+    pub fn hashfull(&self) -> usize {
+        let age = self.age.load(Ordering::Relaxed);
+        let sample = self.ttable.len().min(1000 / BUCKET_SIZE);
+        let used = self.ttable.iter()
+            .take(sample)
+            .flatten()
+            .map(AtomicEntry::load)
+            .filter(|e| e.hash != 0 && e.age == age)
+            .count();
+
+        used * 1000 / (sample * BUCKET_SIZE)
+    }
+}
+
+// the key is stored xored with the data so an entry torn by two threads writing at once no longer matches its hash
+#[derive(Default)]
+pub struct AtomicEntry {
+    key: AtomicU64,
+    data: AtomicU64,
+}
+
+impl AtomicEntry {
     #[inline(always)]
-    pub fn get_hh(&self, piece: u8, to: u8) -> i32 {
-        self.hheuristic[piece as usize][to as usize] 
+    fn load(&self) -> TEntry {
+        let key = self.key.load(Ordering::Relaxed);
+        let data = self.data.load(Ordering::Relaxed);
+
+        TEntry::unpack(key ^ data, data)
     }
 
     #[inline(always)]
-    pub fn inc_hh(&mut self, piece: u8, to: u8, depth: i32) {
-        self.hheuristic[piece as usize][to as usize] += depth*depth
+    fn store(&self, hash: u64, data: u64) {
+        self.key.store(hash ^ data, Ordering::Relaxed);
+        self.data.store(data, Ordering::Relaxed);
     }
 }
 
-#[derive(Copy, Clone, PartialEq)]
+#[derive(Copy, Clone, PartialEq, Debug)]
 pub enum NodeType {
     Pv,
     Alpha,
     Beta,
 }
 
+/*
+--- packed entry data ---
+0-15: best move (from, to, promo piece)
+16-23: depth
+24-25: node type
+26-31: age
+32-63: score
+*/
 #[derive(Copy, Clone)]
 pub struct TEntry {
     hash: u64,
-    best_move: Option<Move>,
-    depth: u8,
-    score: i32,
-    node_type: NodeType,
+    best_move: u16,
+    pub depth: u8,
+    pub score: i32,
+    pub node_type: NodeType,
+    age: u8,
 }
 
 impl TEntry {
-    pub fn new(hash: u64, best_move: Option<Move>, depth: u8, score: i32, node_type: NodeType) -> TEntry {
-        TEntry { hash, best_move, depth, score, node_type }
+    // ply is how far the node is from the root, needed to store mate scores relative to the node
+    pub fn new(hash: u64, best_move: Option<Move>, depth: u8, score: i32, node_type: NodeType, ply: usize) -> TEntry {
+        let best_move = best_move.map_or(0, |m| pack_move(&m));
+        let score = score_to_tt(score, ply);
+        TEntry { hash, best_move, depth, score, node_type, age: 0 }
+    }
+
+    // the move for the position the entry was probed with
+    pub fn get_bestmove(&self, b: &Board) -> Option<Move> {
+        if self.best_move == 0 {
+            None
+        } else {
+            let (from, to, promo_piece) = unpack_move(self.best_move);
+            Some(Move::new_from_squares(from, to, promo_piece, b))
+        }
+    }
+
+    fn pack(&self) -> u64 {
+        let node_type = match self.node_type {
+            NodeType::Pv => 0,
+            NodeType::Alpha => 1,
+            NodeType::Beta => 2,
+        };
+
+        self.best_move as u64
+            | (self.depth as u64) << 16
+            | node_type << 24
+            | ((self.age & AGE_MASK) as u64) << 26
+            | (self.score as u32 as u64) << 32
+    }
+
+    fn unpack(hash: u64, data: u64) -> TEntry {
+        let node_type = match (data >> 24) & 3 {
+            1 => NodeType::Alpha,
+            2 => NodeType::Beta,
+            _ => NodeType::Pv,
+        };
+
+        TEntry {
+            hash,
+            best_move: data as u16,
+            depth: (data >> 16) as u8,
+            score: (data >> 32) as u32 as i32,
+            node_type,
+            age: (data >> 26) as u8 & AGE_MASK,
+        }
+    }
+
+    // lowest value gets replaced first
+    fn replace_value(&self, age: u8) -> i32 {
+        if self.hash == 0 {
+            i32::MIN
+        } else {
+            self.depth as i32 - AGE_PENALTY * (age.wrapping_sub(self.age) & AGE_MASK) as i32
+        }
+    }
+}
+
+// mate and tablebase scores are counted from the root in the search but from the node in the tt,
+// so the same position reached at a different ply still gets the right distance
+fn score_to_tt(score: i32, ply: usize) -> i32 {
+    if score >= eval::TB_WIN_BOUND {
+        score + ply as i32
+    } else if score <= -eval::TB_WIN_BOUND {
+        score - ply as i32
+    } else {
+        score
+    }
+}
+
+fn score_from_tt(score: i32, ply: usize) -> i32 {
+    if score >= eval::TB_WIN_BOUND {
+        score - ply as i32
+    } else if score <= -eval::TB_WIN_BOUND {
+        score + ply as i32
+    } else {
+        score
     }
 }
 
+// 6 bits each for the squares and 4 for the promo piece, 0 is never a real move as from == to
+fn pack_move(m: &Move) -> u16 {
+    m.from as u16 | (m.to as u16) << 6 | (m.promo_piece as u16) << 12
+}
+
+fn unpack_move(m: u16) -> (u8, u8, u8) {
+    ((m & 0x3F) as u8, ((m >> 6) & 0x3F) as u8, (m >> 12) as u8)
+}
+
+
+#[test]
+fn tt_size_test() {
+    for mb in [1, 3, 16] {
+        let tt = TTable::new_with_mb(mb);
+        let bytes = tt.ttable.len() * std::mem::size_of::<Bucket>();
+
+        assert!(bytes <= mb * 1024 * 1024);
+        assert!(bytes + std::mem::size_of::<Bucket>() > mb * 1024 * 1024);
+        assert_eq!(tt.index(u64::MAX), tt.ttable.len() - 1);
+        assert_eq!(tt.index(0), 0);
+    }
+}
+
+#[test]
+fn tt_pack_test() {
+    let tt = TTable::new_with_mb(1);
+    let b = Board::new_with_hash(&tt);
+    let m = Move::new_from_text("g1f3", &b);
+
+    tt.insert(TEntry::new(b.hash, Some(m), 7, -1234, NodeType::Alpha, 0));
+    assert_eq!(tt.get_bestmove(&b), Some(m));
+    assert_eq!(tt.get(b.hash, 7, 0, -1000, 1000), Some(-1000));
+
+    let entry = tt.probe(b.hash).unwrap();
+    assert_eq!((entry.depth, entry.score, entry.node_type), (7, -1234, NodeType::Alpha));
+}
+
+#[test]
+fn tt_replacement_test() {
+    let tt = TTable::new_with_mb(1);
+    let hash = 0x1234_5678_9ABC_DEF0;
+
+    // exact entries are kept
+    tt.insert(TEntry::new(hash, None, 5, 42, NodeType::Pv, 0));
+    assert_eq!(tt.get(hash, 5, 0, -100, 100), Some(42));
+
+    // a much shallower result doesnt overwrite a deep one
+    tt.insert(TEntry::new(hash, None, 1, -7, NodeType::Alpha, 0));
+    assert_eq!(tt.get(hash, 5, 0, -100, 100), Some(42));
+
+    // fill the rest of the bucket with deep entries, then an entry from a new search can still get in
+    let (bucket, len) = (tt.index(hash) as u128, tt.ttable.len() as u128);
+    let same_bucket = |i: u128| ((bucket << 64) / len + 1 + i) as u64;
+    for i in 1..BUCKET_SIZE as u128 {
+        assert_eq!(tt.index(same_bucket(i)), tt.index(hash));
+        tt.insert(TEntry::new(same_bucket(i), None, 20, 0, NodeType::Beta, 0));
+    }
+
+    tt.new_search();
+    let newer = same_bucket(BUCKET_SIZE as u128);
+    tt.insert(TEntry::new(newer, None, 3, 9, NodeType::Pv, 0));
+    assert_eq!(tt.get(newer, 3, 0, -100, 100), Some(9));
+    // the shallowest old entry was the one replaced
+    assert_eq!(tt.get(hash, 5, 0, -100, 100), None);
+
+    // an exact result doesnt overwrite a deep one either, like the depth 0 results quiesce stores
+    let tt = TTable::new_with_mb(1);
+    tt.insert(TEntry::new(hash, None, 10, 30, NodeType::Beta, 0));
+    tt.insert(TEntry::new(hash, None, 0, 5, NodeType::Pv, 0));
+    assert_eq!(tt.get(hash, 10, 0, -100, 20), Some(20));
+}
 
 // #[test]
 // fn ttroubleshooting() {
@@ -1,26 +1,102 @@
 use std::collections::HashMap;
 use std::io::{self, Write};
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+use std::thread::{self, JoinHandle};
 use std::time::Instant;
 
 use crate::{ Board, Move, TTable, Search, TimeControl };
 use crate::opening_book::{self, Book};
+use crate::search::{ SearchSignals, MAX_SEARCH_DEPTH };
+use crate::move_ordering::History;
+use crate::syzygy::Tablebases;
+use crate::transposition_table::DEFAULT_TTABLE_MB;
+
+enum OptionType {
+    Check { default: bool },
+    Spin { default: usize, min: usize, max: usize },
+    Button,
+    String { default: &'static str },
+}
+
+struct UciOption {
+    name: &'static str,
+    option_type: OptionType,
+}
+
+// every option advertised to the gui in uciok
+const OPTIONS: [UciOption; 7] = [
+    UciOption { name: "Hash", option_type: OptionType::Spin { default: DEFAULT_TTABLE_MB, min: 1, max: 65536 } },
+    UciOption { name: "Clear Hash", option_type: OptionType::Button },
+    UciOption { name: "Threads", option_type: OptionType::Spin { default: 1, min: 1, max: 256 } },
+    UciOption { name: "MultiPV", option_type: OptionType::Spin { default: 1, min: 1, max: 256 } },
+    UciOption { name: "OwnBook", option_type: OptionType::Check { default: true } },
+    UciOption { name: "Ponder", option_type: OptionType::Check { default: false } },
+    UciOption { name: "SyzygyPath", option_type: OptionType::String { default: "<empty>" } },
+];
+
+impl UciOption {
+    fn as_uci_string(&self) -> String {
+        match self.option_type {
+            OptionType::Check { default } => format!("option name {} type check default {}", self.name, default),
+            OptionType::Spin { default, min, max } => format!("option name {} type spin default {} min {} max {}", self.name, default, min, max),
+            OptionType::Button => format!("option name {} type button", self.name),
+            OptionType::String { default } => format!("option name {} type string default {}", self.name, default),
+        }
+    }
+}
+
+// current values of the options set by the gui
+pub struct UciOptions {
+    pub hash_mb: usize,
+    pub threads: usize,
+    pub multi_pv: usize,
+    pub own_book: bool,
+    pub ponder: bool,
+    pub syzygy_path: String,
+}
+
+impl UciOptions {
+    pub fn new() -> UciOptions {
+        UciOptions { 
+            hash_mb: DEFAULT_TTABLE_MB, 
+            threads: 1, 
+            multi_pv: 1, 
+            own_book: true, 
+            ponder: false,
+            syzygy_path: String::new(),
+        }
+    }
+}
 
 pub fn uci(author: String, bot_name: String) {
     let mut book_pos = String::new();
     let mut use_book = true;
-    let mut tt = TTable::new(); 
+    let mut options = UciOptions::new();
+    let mut tt = Arc::new(TTable::new_with_mb(options.hash_mb)); 
+    let mut tb = Arc::new(Tablebases::new());
+    let signals = Arc::new(SearchSignals::new());
+    let mut search_thread: Option<JoinHandle<Vec<History>>> = None;
+    // move ordering history for each search thread, handed back once the search is done
+    let mut histories: Vec<History> = Vec::new();
     let mut board: Option<Board> = None;
     let mut buffer = String::new();
     let book = Book::new();
     
     loop {
-        io::stdin()
+        let read = io::stdin()
             .read_line(&mut buffer)
             .expect("Uci input failed");
 
+        // treat the gui closing stdin as a quit
+        if read == 0 {
+            buffer.push_str("quit");
+        }
+
         if buffer.starts_with("ucinewgame") {
             
-            ucinewgame(&mut use_book, &mut tt);            
+            wait_for_search(&mut search_thread, &mut histories);
+            ucinewgame(&mut use_book, &tt, &mut histories);            
             
         } else if buffer.starts_with("uci") {
             
@@ -30,16 +106,34 @@ pub fn uci(author: String, bot_name: String) {
             
             isready();
             
+        } else if buffer.starts_with("setoption") {
+            
+            wait_for_search(&mut search_thread, &mut histories);
+            setoption(&buffer, &mut options, &mut tt, &mut tb);
+            
         } else if buffer.starts_with("position") {
-            //println!("here");
-            board = position(String::from(&buffer), &mut tt, &mut book_pos);
+            
+            wait_for_search(&mut search_thread, &mut histories);
+            board = position(String::from(&buffer), &tt, &mut book_pos);
             
         } else if buffer.starts_with("go") {
             
-            go(board.take().unwrap(), &book_pos, &mut use_book, &mut tt, &book);
+            wait_for_search(&mut search_thread, &mut histories);
+            search_thread = go(&buffer, board.take().unwrap(), &book_pos, &mut use_book, &tt, &tb, &signals, &book, &options, &mut histories);
         
+        } else if buffer.starts_with("stop") {
+            
+            signals.stop.store(true, Ordering::Relaxed);
+            wait_for_search(&mut search_thread, &mut histories);
+            
+        } else if buffer.starts_with("ponderhit") {
+            
+            signals.ponder.store(false, Ordering::Relaxed);
+            
         } else if buffer.starts_with("quit") {
             
+            signals.stop.store(true, Ordering::Relaxed);
+            wait_for_search(&mut search_thread, &mut histories);
             break;
             
         }
@@ -48,27 +142,86 @@ pub fn uci(author: String, bot_name: String) {
     }
 }
 
-fn ucinewgame(use_book: &mut bool, tt: &mut TTable) {
+// blocks until the current search (if any) has printed its bestmove
+fn wait_for_search(search_thread: &mut Option<JoinHandle<Vec<History>>>, histories: &mut Vec<History>) {
+    if let Some(handle) = search_thread.take() {
+        *histories = handle.join().expect("Search thread panicked");
+    }
+}
+
+fn ucinewgame(use_book: &mut bool, tt: &TTable, histories: &mut Vec<History>) {
     *use_book = true;
-    *tt = TTable::new();
+    tt.clear();
+    histories.clear();
 }
 
 fn uciok(author: &str, bot_name: &str) {
-    println!("id name {}\nid author {}\nuciok", bot_name, author); 
+    println!("id name {}\nid author {}", bot_name, author); 
+    for option in &OPTIONS {
+        println!("{}", option.as_uci_string());
+    }
+    println!("uciok");
+}
+
+// setoption name <id> [value <x>], both the name and value can contain spaces
+// only called between searches, so no search thread still holds the tt or tablebases
+fn setoption(buffer: &str, options: &mut UciOptions, tt: &mut Arc<TTable>, tb: &mut Arc<Tablebases>) {
+    let tokens: Vec<&str> = buffer.split_whitespace().collect();
+    let value_index = tokens.iter().position(|t| *t == "value").unwrap_or(tokens.len());
+    let name = tokens.get(2..value_index).unwrap_or_default().join(" ");
+    let value = tokens.get(value_index + 1..).unwrap_or_default().join(" ");
+
+    let Some(option) = OPTIONS.iter().find(|o| o.name.eq_ignore_ascii_case(&name)) else {
+        println!("info string unknown option {}", name);
+        return;
+    };
+
+    let spin = match option.option_type {
+        OptionType::Spin { min, max, .. } => value.parse::<usize>().ok().map(|v| v.clamp(min, max)),
+        _ => None,
+    };
+    let check = value.eq_ignore_ascii_case("true");
+
+    match (option.name, spin) {
+        ("Hash", Some(mb)) => {
+            options.hash_mb = mb;
+            *tt = Arc::new(tt.resize(mb));
+        }
+        ("Clear Hash", _) => tt.clear(),
+        ("Threads", Some(threads)) => options.threads = threads,
+        ("MultiPV", Some(multi_pv)) => options.multi_pv = multi_pv,
+        ("OwnBook", _) => options.own_book = check,
+        ("Ponder", _) => options.ponder = check,
+        ("SyzygyPath", _) => {
+            options.syzygy_path = if value == "<empty>" { String::new() } else { value };
+            *tb = Arc::new(Tablebases::new_from_path(&options.syzygy_path));
+            println!("info string found {} tablebases with up to {} pieces", tb.len(), tb.max_pieces());
+        }
+        _ => println!("info string invalid value {} for {}", value, option.name),
+    }
 }
 
 fn isready() {
     println!("readyok");
 }
 
-fn position(buffer: String, tt: &mut TTable, book_pos: &mut String) -> Option<Board> {
+fn position(buffer: String, tt: &TTable, book_pos: &mut String) -> Option<Board> {
     *book_pos = buffer.trim().to_string();
     
-    let mut board = Board::new_with_hash(tt);
+    let pos: Vec<&str> = buffer.split_whitespace().collect();
+    let moves_index = pos.iter().position(|t| *t == "moves").unwrap_or(pos.len());
+
+    let mut board = match pos.get(1) {
+        Some(&"fen") => {
+            let mut board = Board::new_from_fen(&pos[2..moves_index].join(" "));
+            board.hash = board.get_hash(tt);
+            board
+        }
+        _ => Board::new_with_hash(tt),
+    };
 
-    let pos: Vec<&str> = buffer.trim().split(' ').collect();
-    if pos.len() > 2 {
-        for m in &pos[3..] {
+    if moves_index < pos.len() {
+        for m in &pos[moves_index + 1..] {
             let mv = Move::new_from_text(m, &board);
             board.make(&mv, tt)
         }
@@ -80,17 +233,231 @@ fn position(buffer: String, tt: &mut TTable, book_pos: &mut String) -> Option<Bo
     Some(board)
 }
 
-fn go(board: Board, book_pos: &str, mut use_book: &mut bool, tt: &mut TTable, book: &Book) {
-    if *use_book && book.get_opening_move(book_pos) {
-        *use_book = true;
-        return;
+#[allow(clippy::too_many_arguments)]
+fn go(
+    buffer: &str, 
+    board: Board, 
+    book_pos: &str, 
+    use_book: &mut bool, 
+    tt: &Arc<TTable>, 
+    tb: &Arc<Tablebases>,
+    signals: &Arc<SearchSignals>, 
+    book: &Book,
+    options: &UciOptions,
+    histories: &mut Vec<History>,
+) -> Option<JoinHandle<Vec<History>>> {
+    let tc = parse_go(buffer, board.colour);
+
+    // a book move would be sent straight away, which isnt allowed while pondering
+    if options.own_book && !tc.ponder {
+        if *use_book && book.get_opening_move(book_pos) {
+            *use_book = true;
+            return None;
+        } else {
+            *use_book = false;
+        }
+    }
+
+    let tt = Arc::clone(tt);
+    let tb = Arc::clone(tb);
+    let signals = Arc::clone(signals);
+    let multi_pv = options.multi_pv;
+    let threads = options.threads;
+    signals.stop.store(false, Ordering::Relaxed);
+    signals.ponder.store(tc.ponder, Ordering::Relaxed);
+    signals.nodes.store(0, Ordering::Relaxed);
+
+    let mut histories = std::mem::take(histories);
+    histories.resize_with(threads, History::new);
+
+    // search on its own thread so stop/isready/quit can still be read from stdin
+    let handle = thread::spawn(move || {
+        let (tt, tb, signals) = (&*tt, &*tb, &*signals);
+
+        // lazy smp, the helpers search the same position silently and share what they find through the tt
+        let mut histories = histories.into_iter();
+        let main_history = histories.next().unwrap();
+
+        let (pv, histories) = thread::scope(|s| {
+            let helpers: Vec<_> = histories.enumerate().map(|(i, history)| {
+                let board = board.clone();
+                s.spawn(move || {
+                    let mut helper = Search::new_with_history(board, tt, tc, signals, history);
+                    helper.thread_id = i + 1;
+                    helper.tb = Some(tb);
+                    helper.iterative_deepening_search();
+                    helper.history
+                })
+            }).collect();
+
+            let mut search = Search::new_with_history(board, tt, tc, signals, main_history);
+            search.multi_pv = multi_pv;
+            search.tb = Some(tb);
+
+            let pv = search.iterative_deepening_search();
+            signals.stop.store(true, Ordering::Relaxed);
+
+            let mut histories = vec![search.history];
+            histories.extend(helpers.into_iter().map(|h| h.join().expect("Helper thread panicked")));
+            (pv, histories)
+        });
+        
+        match (pv.first(), pv.get(1)) {
+            (Some(best_move), Some(ponder_move)) => println!("bestmove {} ponder {}", best_move.as_uci_string(), ponder_move.as_uci_string()),
+            (Some(best_move), None) => println!("bestmove {}", best_move.as_uci_string()),
+            // no legal moves
+            _ => println!("bestmove 0000"),
+        }
+
+        histories
+    });
+
+    Some(handle)
+}
+
+fn parse_go(buffer: &str, colour: usize) -> TimeControl {
+    let start_time = Instant::now();
+    let mut clock = [None, None];
+    let mut inc = [0, 0];
+    let mut movestogo = None;
+    let mut movetime = None;
+    let mut depth = None;
+    let mut nodes = None;
+    let mut infinite = false;
+    let mut ponder = false;
+
+    let tokens: Vec<&str> = buffer.split_whitespace().collect();
+    for (i, token) in tokens.iter().enumerate() {
+        let value = tokens.get(i + 1).and_then(|v| v.parse::<i64>().ok());
+        // clocks can go negative when a gui is running late
+        let value = value.map(|v| v.max(0) as u64);
+
+        match *token {
+            "wtime" => clock[0] = value,
+            "btime" => clock[1] = value,
+            "winc" => inc[0] = value.unwrap_or(0),
+            "binc" => inc[1] = value.unwrap_or(0),
+            "movestogo" => movestogo = value,
+            "movetime" => movetime = value,
+            "depth" => depth = value,
+            "nodes" => nodes = value,
+            "infinite" => infinite = true,
+            "ponder" => ponder = true,
+            _ => {}
+        }
+    }
+
+    let mut tc = if let Some(movetime) = movetime {
+        TimeControl::new_movetime(start_time, movetime)
+    } else if let (Some(time_left), false) = (clock[colour], infinite) {
+        TimeControl::new_clock(start_time, time_left, inc[colour], movestogo)
+    } else if infinite || depth.is_some() || nodes.is_some() {
+        TimeControl::new_unlimited(start_time)
     } else {
-        *use_book = false;
+        TimeControl::new(start_time)
+    };
+
+    if let Some(depth) = depth {
+        tc.max_depth = (depth as usize).clamp(1, MAX_SEARCH_DEPTH - 1);
     }
+    if let Some(nodes) = nodes {
+        tc.max_nodes = nodes;
+    }
+    tc.infinite = infinite;
+    tc.ponder = ponder;
 
-    let mut search = Search::new(board, tt, TimeControl::new_now());
+    tc
+}
 
-    let mut best_move = search.iterative_deepening_search();
+#[test]
+fn position_fen_test() {
+    let tt = TTable::new();
+    let mut book_pos = String::new();
+
+    let fen = "rnbqkbnr/ppp1pppp/8/8/3pP3/5N2/PPPP1PPP/RNBQKB1R b KQkq e3 0 3";
+    let board = position(format!("position fen {fen}\n"), &tt, &mut book_pos).unwrap();
+    assert_eq!(board.colour, 1);
+    assert_eq!(board.ep, 20);
+    assert_eq!(board.hash, board.get_hash(&tt));
+
+    // ep capture then a few moves should land on the same position as the fen
+    let board = position(format!("position fen {fen} moves d4e3 d2e3 g8f6\n"), &tt, &mut book_pos).unwrap();
+    let expected = Board::new_from_fen("rnbqkb1r/ppp1pppp/5n2/8/8/4PN2/PPP2PPP/RNBQKB1R w KQkq - 1 5");
+    assert_eq!(board.pieces, expected.pieces);
+    assert_eq!(board.colour, 0);
+    assert_eq!(board.hash, expected.get_hash(&tt));
+
+    let board = position(String::from("position startpos moves e2e4\n"), &tt, &mut book_pos).unwrap();
+    assert_eq!(board.pieces, Board::new_from_fen("rnbqkbnr/pppppppp/8/8/4P3/8/PPPP1PPP/RNBQKBNR b KQkq e3 0 1").pieces);
+}
+
+#[test]
+fn parse_go_test() {
+    let tc = parse_go("go wtime 60000 btime 1000 winc 1000 binc 0\n", 0);
+    assert!(tc.soft_lim <= tc.hard_lim);
+    assert!(tc.soft_lim.as_millis() > 1000);
+    assert!(tc.hard_lim.as_millis() < 60000);
+
+    // black is short on time so should move quickly
+    let tc = parse_go("go wtime 60000 btime 1000 winc 1000 binc 0\n", 1);
+    assert!(tc.hard_lim.as_millis() < 1000);
+
+    // last move before the time control can use most of the clock but not all of it
+    let tc = parse_go("go wtime 10000 btime 10000 movestogo 1\n", 0);
+    assert!(tc.soft_lim.as_millis() > 5000);
+    assert!(tc.hard_lim.as_millis() < 10000);
+}
+
+#[test]
+fn parse_go_limits_test() {
+    let tc = parse_go("go depth 8\n", 0);
+    assert_eq!(tc.max_depth, 8);
+    assert_eq!(tc.hard_lim, std::time::Duration::MAX);
+
+    let tc = parse_go("go nodes 10000\n", 1);
+    assert_eq!(tc.max_nodes, 10000);
+    assert_eq!(tc.hard_lim, std::time::Duration::MAX);
+
+    let tc = parse_go("go movetime 1000\n", 0);
+    assert_eq!(tc.soft_lim, tc.hard_lim);
+    assert!(tc.hard_lim.as_millis() <= 1000);
+
+    let tc = parse_go("go infinite\n", 0);
+    assert_eq!(tc.soft_lim, std::time::Duration::MAX);
+    assert!(tc.infinite);
+
+    // pondering still gets a normal time allocation for after the ponderhit
+    let tc = parse_go("go ponder wtime 10000 btime 10000\n", 0);
+    assert!(tc.ponder);
+    assert!(tc.hard_lim.as_millis() < 10000);
+}
+
+#[test]
+fn setoption_test() {
+    let mut options = UciOptions::new();
+    let mut tt = Arc::new(TTable::new_with_mb(1));
+    let mut tb = Arc::new(Tablebases::new());
+
+    let board = Board::new_with_hash(&tt);
+    setoption("setoption name Hash value 2\n", &mut options, &mut tt, &mut tb);
+    assert_eq!(options.hash_mb, 2);
+    // a board set up before the resize still hashes the same
+    assert_eq!(board.hash, board.get_hash(&tt));
+    assert!(tt.ttable.len() * std::mem::size_of_val(&tt.ttable[0]) <= 2 * 1024 * 1024);
+
+    setoption("setoption name multipv value 3\n", &mut options, &mut tt, &mut tb);
+    assert_eq!(options.multi_pv, 3);
     
-    println!("bestmove {}", best_move.unwrap().as_uci_string());
+    setoption("setoption name Threads value 1000\n", &mut options, &mut tt, &mut tb);
+    assert_eq!(options.threads, 256);
+
+    setoption("setoption name OwnBook value false\n", &mut options, &mut tt, &mut tb);
+    assert!(!options.own_book);
+
+    setoption("setoption name Clear Hash\n", &mut options, &mut tt, &mut tb);
+    assert_eq!(tt.hashfull(), 0);
+
+    setoption("setoption name SyzygyPath value /nonexistent/syzygy\n", &mut options, &mut tt, &mut tb);
+    assert_eq!(options.syzygy_path, "/nonexistent/syzygy");
+    assert_eq!(tb.max_pieces(), 0);
 }